@@ -1,17 +1,92 @@
 use argh::FromArgs;
 use log::{error, info};
 use phargs::*;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, PartialEq)]
 struct Xargs(Vec<String>);
 
 impl std::str::FromStr for Xargs {
     type Err = std::convert::Infallible;
-    /// comma separated
+    /// comma separated, with `\,` for a literal comma
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Xargs(
-            s.split(',').map(|s| s.to_string()).collect::<Vec<_>>(),
-        ))
+        Ok(Xargs(comma_separated_escaped(s)))
+    }
+}
+
+/// A named list given as `name=a,b,c`.
+#[derive(Debug, PartialEq)]
+struct NamedList(String, Vec<String>);
+
+impl std::str::FromStr for NamedList {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, list) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=LIST, got `{}`", s))?;
+        Ok(NamedList(name.to_string(), comma_separated_escaped(list)))
+    }
+}
+
+/// Exit codes given as `0,1,...`.
+#[derive(Debug, PartialEq)]
+struct Codes(Vec<i32>);
+
+impl std::str::FromStr for Codes {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|code| {
+                code.trim()
+                    .parse()
+                    .map_err(|_| format!("invalid exit code `{}`", code))
+            })
+            .collect::<Result<_, _>>()
+            .map(Codes)
+    }
+}
+
+/// A shard `I/K` of the batch, with `I` counted from 1.
+#[derive(Debug, PartialEq)]
+struct Shard(usize, usize);
+
+impl std::str::FromStr for Shard {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected I/K with 1 <= I <= K, got `{}`", s);
+        let (i, k) = s.split_once('/').ok_or_else(error)?;
+        let (i, k) = (
+            i.parse().map_err(|_| error())?,
+            k.parse().map_err(|_| error())?,
+        );
+        if i == 0 || i > k {
+            return Err(error());
+        }
+        Ok(Shard(i, k))
+    }
+}
+
+/// A range `MIN-MAX` of delays in seconds, like `0.5-2`.
+#[derive(Debug, PartialEq)]
+struct JitterRange(Duration, Duration);
+
+impl std::str::FromStr for JitterRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected MIN-MAX seconds with MIN <= MAX, got `{}`", s);
+        let (min, max) = s.split_once('-').ok_or_else(error)?;
+        let seconds = |s: &str| {
+            s.trim()
+                .parse()
+                .ok()
+                .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                .ok_or_else(error)
+        };
+        let (min, max) = (seconds(min)?, seconds(max)?);
+        if min > max {
+            return Err(error());
+        }
+        Ok(JitterRange(min, max))
     }
 }
 
@@ -19,42 +94,1654 @@ impl std::str::FromStr for Xargs {
 /// Multiple command runner in one line
 struct Args {
     #[argh(option, short = 'w')]
-    /// comma separated arguments
-    wlist: Xargs,
+    /// comma separated arguments, with \, for a literal comma
+    wlist: Option<Xargs>,
+
+    #[argh(option, long = "list")]
+    /// named list NAME=a,b,... substituted for {{NAME}}, zipped with other lists
+    lists: Vec<NamedList>,
+
+    #[argh(option, short = 'a')]
+    /// read arguments line by line from a file, or stdin for -; {{.line}} is the line number
+    arg_file: Option<String>,
+
+    #[argh(option)]
+    /// read arguments from the output of a $SHELL script, split by lines or NUL
+    from_command: Option<String>,
+
+    #[argh(option)]
+    /// read arguments from the environment variable VAR, comma separated like -w
+    from_env: Option<String>,
+
+    #[argh(switch)]
+    /// read arguments from stdin separated by whitespace, with quotes like xargs
+    whitespace: bool,
+
+    #[cfg(feature = "serde")]
+    #[argh(switch)]
+    /// read arguments from stdin as a JSON array of strings
+    json_input: bool,
+
+    #[cfg(feature = "serde")]
+    #[argh(option)]
+    /// write the commands to FILE as JSON lines instead of running them
+    plan: Option<String>,
+
+    #[cfg(feature = "serde")]
+    #[argh(option)]
+    /// run exactly the commands of a FILE written by --plan
+    apply: Option<String>,
 
     #[argh(switch, short = 'n')]
     /// dry run
     dry_run: bool,
 
-    /// actual running command
+    #[argh(option)]
+    /// with --dry-run, wrap commands longer than N columns onto lines ending with \
+    wrap_width: Option<usize>,
+
+    #[argh(switch)]
+    /// run the command as a $SHELL script, or each argument if no command is given
+    shell: bool,
+
+    #[argh(switch)]
+    /// exit successfully instead of failing when no command is given
+    allow_empty: bool,
+
+    #[argh(switch)]
+    /// print the program, the unexpanded arguments and the number of arguments to substitute
+    list_commands: bool,
+
+    #[argh(switch)]
+    /// dry run that marks the substituted parts of each command as <<..>>
+    dry_run_diff: bool,
+
+    #[argh(switch)]
+    /// dry run that prints the argv size in bytes, as counted by --max-chars, before each command
+    show_sizes: bool,
+
+    #[argh(switch)]
+    /// dry run that also reports missing programs and unresolved placeholders
+    check: bool,
+
+    #[argh(option)]
+    /// use this string instead of {{}} in the command where it isn't part of a word (default: $PHARGS_PLACEHOLDER)
+    placeholder: Option<String>,
+
+    #[argh(switch)]
+    /// substitute placeholders in an argument only after its first =
+    value_only: bool,
+
+    #[argh(option)]
+    /// split each argument into fields {{1}}, {{2}}, ... by this delimiter
+    field_delim: Option<String>,
+
+    #[argh(option)]
+    /// in [..] arguments, expand a field once per item split by this delimiter
+    sub_delim: Option<String>,
+
+    #[argh(option, default = "1")]
+    /// first value of the {{#}} counter
+    count_start: i64,
+
+    #[argh(option, default = "1")]
+    /// increment of the {{#}} counter
+    count_step: i64,
+
+    #[argh(option)]
+    /// run once with {{}} substituted by all arguments joined by this separator
+    join: Option<String>,
+
+    #[argh(option)]
+    /// run each command under this program, like time or nice; repeatable
+    wrap: Vec<String>,
+
+    #[argh(switch)]
+    /// without arguments, keep [..] arguments as their literal content and run once
+    keep_empty_brackets: bool,
+
+    #[argh(switch)]
+    /// expand {{a,b}} sets in arguments into one argument per alternative
+    brace_sets: bool,
+
+    #[argh(option)]
+    /// run the program with this argv[0] (Unix only)
+    arg0: Option<String>,
+
+    #[argh(option)]
+    /// directory {{rel}} makes arguments relative to (default: the current directory)
+    base: Option<String>,
+
+    #[argh(switch)]
+    /// start the commands with an empty environment
+    clear_env: bool,
+
+    #[argh(option)]
+    /// with --clear-env, keep this environment variable; repeatable
+    keep_env: Vec<String>,
+
+    #[argh(option)]
+    /// set PATH of the commands, which is also used to find their programs
+    path: Option<String>,
+
+    #[argh(switch)]
+    /// fail before running if {{size}} or {{mtime}} names a file that doesn't exist
+    strict_metadata: bool,
+
+    #[argh(switch)]
+    /// fail before running if a template has a warning, such as an unresolved placeholder
+    deny_warnings: bool,
+
+    #[argh(switch)]
+    /// drop the first argument, such as a CSV header
+    skip_header: bool,
+
+    #[cfg(feature = "nice")]
+    #[argh(option)]
+    /// lower the priority of commands by this niceness increment (Unix only)
+    nice: Option<i32>,
+
+    #[argh(switch)]
+    /// sort arguments lexicographically before running
+    sort: bool,
+
+    #[argh(switch)]
+    /// sort arguments numerically before running
+    numeric_sort: bool,
+
+    #[argh(switch)]
+    /// look up the program next to the phargs executable first
+    sibling: bool,
+
+    #[argh(option)]
+    /// run only shard I of K contiguous parts of the arguments, like 2/4
+    shard: Option<Shard>,
+
+    #[argh(option)]
+    /// record arguments whose command succeeded in FILE, and skip those already in it
+    checkpoint: Option<String>,
+
+    #[argh(switch)]
+    /// run each command in its argument as the working directory, skipping arguments that aren't directories
+    chdir_per_pharg: bool,
+
+    #[argh(switch)]
+    /// skip commands identical to an earlier one
+    dedup_commands: bool,
+
+    #[argh(switch)]
+    /// connect the standard streams of commands to the terminal (default unless output is captured)
+    inherit_stdio: bool,
+
+    #[argh(switch)]
+    /// connect the standard streams of commands to the null device
+    null_stdio: bool,
+
+    #[argh(option)]
+    /// pass the arguments as @FILE, with FILE in the temp directory named after this template, which must expand to a plain file name
+    response_file: Option<String>,
+
+    #[cfg(unix)]
+    #[argh(switch)]
+    /// pass SIGINT, SIGTERM and SIGHUP on to the running command, then stop
+    forward_signals: bool,
+
+    #[argh(option)]
+    /// write this template, substituted like the command, to the stdin of each command
+    stdin_template: Option<String>,
+
+    #[argh(switch)]
+    /// capture stderr of commands together with stdout, in the order written
+    merge_stderr: bool,
+
+    #[argh(option)]
+    /// with --merge-stderr or --merged-output, keep at most BYTES of the output of each command and discard the rest
+    max_capture: Option<usize>,
+
+    #[argh(option)]
+    /// prefix each output line with a template supporting {{}} and {{#}}
+    output_prefix: Option<String>,
+
+    #[argh(option)]
+    /// maximum argv size in bytes when appending arguments with [..]
+    max_chars: Option<usize>,
+
+    #[argh(option)]
+    /// fail without running anything if more than N commands would run
+    max_commands: Option<usize>,
+
+    #[argh(switch)]
+    /// show a preview of the commands and ask once before running them
+    confirm: bool,
+
+    #[argh(option)]
+    /// $SHELL script to run once before the commands; they don't run if it fails
+    pre: Option<String>,
+
+    #[argh(option)]
+    /// $SHELL script to run once after the commands
+    post: Option<String>,
+
+    #[argh(option)]
+    /// start at most N commands per second
+    rate: Option<f64>,
+
+    #[argh(option)]
+    /// sleep a random duration between MIN-MAX seconds before each command
+    jitter: Option<JitterRange>,
+
+    #[argh(switch)]
+    /// stop after the first command that succeeds, failing only if none does
+    first_success: bool,
+
+    #[argh(option)]
+    /// comma separated exit codes that count as success (default: 0)
+    success_codes: Option<Codes>,
+
+    #[argh(option)]
+    /// exit code to use when a command fails (default: the command's own)
+    fail_code: Option<i32>,
+
+    #[argh(option)]
+    /// write a CSV report of the commands run, with exit code and duration
+    csv: Option<String>,
+
+    #[argh(option)]
+    /// capture the stdout of each command into FILE, after a "=== command ===" header line
+    merged_output: Option<String>,
+
+    #[argh(switch)]
+    /// print a summary of command timings
+    timings: bool,
+
+    #[cfg(feature = "serde")]
+    #[argh(switch)]
+    /// log start, done and fail events of each command to stderr as JSON lines
+    log_json: bool,
+
+    #[argh(switch)]
+    /// print a line before and after running each command
+    trace: bool,
+
+    #[argh(switch)]
+    /// print the number of commands to stderr before running them, as "phargs: N commands"
+    announce_count: bool,
+
+    #[argh(switch, short = 'v')]
+    /// verbose output
+    verbose: bool,
+
+    /// actual running command; put it after -- if it starts with a dash
     #[argh(positional, greedy)]
     command: Vec<String>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opts: Args = argh::from_env();
+/// Exit code for invalid command lines.
+const USAGE_EXIT_CODE: i32 = 2;
+
+/// Returns true if the exit `code` of a command is one of `set`.
+///
+/// A command killed by a signal has no exit code and never succeeds.
+fn is_success(code: Option<i32>, set: &[i32]) -> bool {
+    code.is_some_and(|code| set.contains(&code))
+}
+
+/// Selects the exit code of phargs after a command failed with `code`.
+///
+/// `fail_code` overrides the command's exit code. A command killed by a
+/// signal has no exit code and maps to 1.
+fn failure_exit_code(code: Option<i32>, fail_code: Option<i32>) -> i32 {
+    fail_code.or(code).unwrap_or(1)
+}
+
+/// Picks the placeholder token: `--placeholder`, else `PHARGS_PLACEHOLDER`.
+///
+/// Returns `None` for the default `{}`, including when the variable is empty.
+fn placeholder_token(flag: Option<String>, env: Option<String>) -> Option<String> {
+    flag.or(env.filter(|token| !token.is_empty()))
+}
 
-    if opts.command.is_empty() {
-        return Err("command is empty".into());
+/// Replaces `token` in `arg` with `{}`, except where it is part of a word,
+/// that is, next to an alphanumeric character, such as `%` in `100%`.
+fn replace_placeholder(arg: &str, token: &str) -> String {
+    let mut out = String::with_capacity(arg.len());
+    let mut last = 0;
+    for (start, _) in arg.match_indices(token) {
+        let end = start + token.len();
+        let before = arg[..start].chars().next_back();
+        let after = arg[end..].chars().next();
+        if before.is_some_and(char::is_alphanumeric) || after.is_some_and(char::is_alphanumeric) {
+            continue;
+        }
+        out.push_str(&arg[last..start]);
+        out.push_str("{}");
+        last = end;
     }
+    out.push_str(&arg[last..]);
+    out
+}
 
-    let mut command = opts.command;
-    let args = command.split_off(1);
+/// Returns the path of the `--response-file` `name` of the command `index`.
+///
+/// The file is created in the temp directory, with a prefix that makes its
+/// name unique to this process and command.
+fn response_file_path(name: &str, index: usize) -> Result<std::path::PathBuf, String> {
+    let mut components = std::path::Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(_)), None) if !name.contains(['/', '\\']) => {
+            let unique = format!("phargs-{}-{}-{}", std::process::id(), index, name);
+            Ok(std::env::temp_dir().join(unique))
+        }
+        _ => Err(format!(
+            "--response-file must expand to a file name, got `{}`",
+            name
+        )),
+    }
+}
 
-    let commands = PhCommandVec::new(&command[0], args, opts.wlist.0);
+/// Decides whether to exit successfully without running anything because the
+/// command is `empty` and `--allow-empty` is set. An empty command is a usage
+/// error otherwise.
+fn skip_empty_command(empty: bool, allow_empty: bool) -> Result<bool, &'static str> {
+    match (empty, allow_empty) {
+        (false, _) => Ok(false),
+        (true, true) => Ok(true),
+        (true, false) => Err("command is empty"),
+    }
+}
 
-    for a in commands.iter() {
-        if opts.dry_run {
+/// Fails if `count` commands exceed the `--max-commands` limit `max`.
+fn check_max_commands(count: usize, max: Option<usize>) -> Result<(), String> {
+    match max {
+        Some(max) if count > max => {
+            Err(format!("{} commands exceed --max-commands {}", count, max))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// How many commands are shown at each end of the `--confirm` preview.
+const PREVIEW_EDGE: usize = 3;
+
+/// Formats the `--confirm` preview of `commands`, eliding the middle of long batches.
+fn confirm_preview(commands: &[String]) -> String {
+    let lines = if commands.len() <= 2 * PREVIEW_EDGE + 1 {
+        commands.to_vec()
+    } else {
+        let mut lines = commands[..PREVIEW_EDGE].to_vec();
+        lines.push(format!("... {} more", commands.len() - 2 * PREVIEW_EDGE));
+        lines.extend_from_slice(&commands[commands.len() - PREVIEW_EDGE..]);
+        lines
+    };
+    let mut preview = format!("About to run {} commands:\n", commands.len());
+    for line in lines {
+        preview.push_str(&format!("  {}\n", line));
+    }
+    preview
+}
+
+/// Returns true if `answer` to the `--confirm` prompt means yes.
+fn is_confirmed(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Selects how commands are connected to the standard streams.
+///
+/// `--null-stdio` wins over `--inherit-stdio`. Without either, the streams
+/// are inherited unless the output is `captured`.
+fn stdio_mode(inherit: bool, null: bool, captured: bool) -> StdioMode {
+    if null {
+        StdioMode::Null
+    } else if inherit || !captured {
+        StdioMode::Inherit
+    } else {
+        StdioMode::Default
+    }
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let opts = match Args::from_args(&["phargs"], &args[1..]) {
+        Ok(opts) => opts,
+        Err(argh::EarlyExit {
+            output,
+            status: Ok(()),
+        }) => {
+            println!("{}", output);
+            return;
+        }
+        Err(argh::EarlyExit { output, .. }) => usage_error(&format!(
+            "{}\nRun phargs --help for more information.",
+            output.trim_end()
+        )),
+    };
+    if let Err(e) = run(opts) {
+        eprintln!("phargs: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Prints `message` and exits with [`USAGE_EXIT_CODE`].
+fn usage_error(message: &str) -> ! {
+    eprintln!("phargs: {}", message);
+    std::process::exit(USAGE_EXIT_CODE);
+}
+
+/// The arguments substituted into the command, by the kind of their source.
+enum Phargs {
+    List(Vec<String>),
+    Lines(Vec<(usize, String)>),
+    Named(std::collections::HashMap<String, Vec<String>>),
+}
+
+/// Reads the phargs from the one source selected by `opts`.
+fn read_phargs(opts: &mut Args) -> Result<Phargs, Box<dyn std::error::Error>> {
+    #[cfg(feature = "serde")]
+    let json_input = opts.json_input;
+    #[cfg(not(feature = "serde"))]
+    let json_input = false;
+
+    let sources = [
+        opts.wlist.is_some(),
+        !opts.lists.is_empty(),
+        opts.arg_file.is_some(),
+        opts.from_command.is_some(),
+        opts.from_env.is_some(),
+        opts.whitespace,
+        json_input,
+    ];
+    if sources.iter().filter(|s| **s).count() != 1 {
+        usage_error(
+            "exactly one of -w, --list, --arg-file, --from-command, --from-env, --whitespace or --json-input is required",
+        );
+    }
+
+    if let Some(wlist) = opts.wlist.take() {
+        return Ok(Phargs::List(wlist.0));
+    }
+    if !opts.lists.is_empty() {
+        let lists = std::mem::take(&mut opts.lists);
+        return Ok(Phargs::Named(
+            lists.into_iter().map(|l| (l.0, l.1)).collect(),
+        ));
+    }
+    if let Some(script) = opts.from_command.take() {
+        let (program, args) = shell_command(&default_shell(), &[script]);
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+        return Ok(Phargs::List(read_command_output(command)?));
+    }
+    if let Some(name) = opts.from_env.take() {
+        return Ok(Phargs::List(phargs_from_env(&name)?));
+    }
+    if opts.whitespace {
+        return Ok(Phargs::List(read_words(std::io::stdin().lock())?));
+    }
+    #[cfg(feature = "serde")]
+    if json_input {
+        return Ok(Phargs::List(read_json_array(std::io::stdin().lock())?));
+    }
+    let path = opts.arg_file.take().unwrap_or_default();
+    let lines = if path == "-" {
+        read_lines(std::io::stdin().lock())?
+    } else {
+        let file = std::fs::File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+        read_lines(std::io::BufReader::new(file))?
+    };
+    Ok(Phargs::Lines(lines))
+}
+
+/// Reads the `--from-env` phargs from the environment variable `name`.
+fn phargs_from_env(name: &str) -> Result<Vec<String>, String> {
+    match std::env::var(name) {
+        Ok(value) => Ok(comma_separated_escaped(&value)),
+        Err(std::env::VarError::NotPresent) => Err(format!("${} is not set", name)),
+        Err(e) => Err(format!("${}: {}", name, e)),
+    }
+}
+
+/// Runs the commands of the plan at `path`, stopping at the first failure,
+/// or only prints them with `dry_run`.
+#[cfg(feature = "serde")]
+fn apply_plan(
+    path: &str,
+    dry_run: bool,
+    success_codes: &[i32],
+    fail_code: Option<i32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let argvs = read_plan(std::io::BufReader::new(file))?;
+    if dry_run {
+        for argv in &argvs {
+            println!("{}", argv.join(" "));
+        }
+        return Ok(());
+    }
+    for argv in argvs {
+        let (program, args) = argv.split_first().ok_or("empty argv in the plan")?;
+        let status = std::process::Command::new(program).args(args).status()?;
+        if !is_success(status.code(), success_codes) {
+            std::process::exit(failure_exit_code(status.code(), fail_code));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the options that decide which commands are built, and are
+/// therefore already part of a plan, with whether each of them is set.
+#[cfg(feature = "serde")]
+fn build_options(opts: &Args) -> Vec<(&'static str, bool)> {
+    vec![
+        ("-w", opts.wlist.is_some()),
+        ("--list", !opts.lists.is_empty()),
+        ("--arg-file", opts.arg_file.is_some()),
+        ("--from-command", opts.from_command.is_some()),
+        ("--from-env", opts.from_env.is_some()),
+        ("--whitespace", opts.whitespace),
+        ("--json-input", opts.json_input),
+        ("--plan", opts.plan.is_some()),
+        ("--shell", opts.shell),
+        ("--allow-empty", opts.allow_empty),
+        ("--placeholder", opts.placeholder.is_some()),
+        ("--value-only", opts.value_only),
+        ("--field-delim", opts.field_delim.is_some()),
+        ("--sub-delim", opts.sub_delim.is_some()),
+        ("--count-start", opts.count_start != 1),
+        ("--count-step", opts.count_step != 1),
+        ("--join", opts.join.is_some()),
+        ("--wrap", !opts.wrap.is_empty()),
+        ("--keep-empty-brackets", opts.keep_empty_brackets),
+        ("--brace-sets", opts.brace_sets),
+        ("--base", opts.base.is_some()),
+        ("--strict-metadata", opts.strict_metadata),
+        ("--deny-warnings", opts.deny_warnings),
+        ("--skip-header", opts.skip_header),
+        ("--sort", opts.sort),
+        ("--numeric-sort", opts.numeric_sort),
+        ("--sibling", opts.sibling),
+        ("--shard", opts.shard.is_some()),
+        ("--dedup-commands", opts.dedup_commands),
+        ("--max-chars", opts.max_chars.is_some()),
+        ("--max-commands", opts.max_commands.is_some()),
+        ("a command", !opts.command.is_empty()),
+    ]
+}
+
+/// Returns the options that decide how commands are run, which a plan
+/// doesn't record, with whether each of them is set.
+#[cfg(feature = "serde")]
+fn run_options(opts: &Args) -> Vec<(&'static str, bool)> {
+    let mut options = vec![
+        ("--wrap-width", opts.wrap_width.is_some()),
+        ("--list-commands", opts.list_commands),
+        ("--dry-run-diff", opts.dry_run_diff),
+        ("--show-sizes", opts.show_sizes),
+        ("--check", opts.check),
+        ("--arg0", opts.arg0.is_some()),
+        ("--clear-env", opts.clear_env),
+        ("--keep-env", !opts.keep_env.is_empty()),
+        ("--path", opts.path.is_some()),
+        ("--checkpoint", opts.checkpoint.is_some()),
+        ("--chdir-per-pharg", opts.chdir_per_pharg),
+        ("--inherit-stdio", opts.inherit_stdio),
+        ("--null-stdio", opts.null_stdio),
+        ("--response-file", opts.response_file.is_some()),
+        ("--stdin-template", opts.stdin_template.is_some()),
+        ("--merge-stderr", opts.merge_stderr),
+        ("--max-capture", opts.max_capture.is_some()),
+        ("--output-prefix", opts.output_prefix.is_some()),
+        ("--confirm", opts.confirm),
+        ("--pre", opts.pre.is_some()),
+        ("--post", opts.post.is_some()),
+        ("--rate", opts.rate.is_some()),
+        ("--jitter", opts.jitter.is_some()),
+        ("--first-success", opts.first_success),
+        ("--success-codes", opts.success_codes.is_some()),
+        ("--fail-code", opts.fail_code.is_some()),
+        ("--csv", opts.csv.is_some()),
+        ("--merged-output", opts.merged_output.is_some()),
+        ("--timings", opts.timings),
+        ("--log-json", opts.log_json),
+        ("--trace", opts.trace),
+        ("--announce-count", opts.announce_count),
+        ("-v", opts.verbose),
+    ];
+    #[cfg(feature = "nice")]
+    options.push(("--nice", opts.nice.is_some()));
+    #[cfg(unix)]
+    options.push(("--forward-signals", opts.forward_signals));
+    options
+}
+
+/// Returns the first option that `--plan` or `--apply` would silently drop.
+///
+/// `--plan` saves only the argv of each command, so options deciding how
+/// they run are rejected. `--apply` runs the plan as it is, honoring only
+/// `-n`, `--success-codes` and `--fail-code`.
+#[cfg(feature = "serde")]
+fn plan_conflict(opts: &Args) -> Option<&'static str> {
+    let applied = ["--success-codes", "--fail-code"];
+    let conflicts = if opts.apply.is_some() {
+        let run = run_options(opts).into_iter();
+        build_options(opts)
+            .into_iter()
+            .chain(run.filter(|(name, _)| !applied.contains(name)))
+            .collect()
+    } else if opts.plan.is_some() {
+        run_options(opts)
+    } else {
+        Vec::new()
+    };
+    conflicts
+        .into_iter()
+        .find(|(_, set)| *set)
+        .map(|(name, _)| name)
+}
+
+/// Returns the first option that `--first-success` would ignore, since it
+/// runs the commands without the reporting and pacing of the main loop.
+fn first_success_conflict(opts: &Args) -> Option<&'static str> {
+    let mut options = vec![
+        ("--csv", opts.csv.is_some()),
+        ("--timings", opts.timings),
+        ("--trace", opts.trace),
+        ("--checkpoint", opts.checkpoint.is_some()),
+        ("--rate", opts.rate.is_some()),
+        ("--jitter", opts.jitter.is_some()),
+        ("--merge-stderr", opts.merge_stderr),
+        ("--max-capture", opts.max_capture.is_some()),
+        ("--output-prefix", opts.output_prefix.is_some()),
+        ("--merged-output", opts.merged_output.is_some()),
+        ("--response-file", opts.response_file.is_some()),
+    ];
+    #[cfg(feature = "serde")]
+    options.push(("--log-json", opts.log_json));
+    #[cfg(unix)]
+    options.push(("--forward-signals", opts.forward_signals));
+    options
+        .into_iter()
+        .find(|(_, set)| *set)
+        .map(|(name, _)| name)
+}
+
+/// Runs a `--pre` or `--post` command, failing if it does.
+fn run_hook(
+    command: Option<std::process::Command>,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(mut command) = command {
+        let status = command.status()?;
+        if !status.success() {
+            return Err(format!("{} failed: {}", name, status).into());
+        }
+    }
+    Ok(())
+}
+
+fn run(mut opts: Args) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "serde")]
+    if let Some(name) = plan_conflict(&opts) {
+        let mode = if opts.apply.is_some() {
+            "--apply"
+        } else {
+            "--plan"
+        };
+        usage_error(&format!("{} can't be combined with {}", mode, name));
+    }
+    #[cfg(feature = "serde")]
+    if let Some(path) = &opts.apply {
+        let success_codes = opts.success_codes.take().map_or(vec![0], |codes| codes.0);
+        return apply_plan(path, opts.dry_run, &success_codes, opts.fail_code);
+    }
+
+    match skip_empty_command(opts.command.is_empty() && !opts.shell, opts.allow_empty) {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(message) => usage_error(message),
+    }
+    if opts.rate.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+        usage_error("--rate must be positive");
+    }
+    if opts
+        .rate
+        .is_some_and(|rate| Duration::try_from_secs_f64(1.0 / rate).is_err())
+    {
+        usage_error("--rate is too small");
+    }
+    if let Some(name) = first_success_conflict(&opts).filter(|_| opts.first_success) {
+        usage_error(&format!("--first-success can't be combined with {}", name));
+    }
+    if opts.arg0.is_some() && !opts.wrap.is_empty() {
+        usage_error("--arg0 can't be combined with --wrap");
+    }
+    if opts.wrap_width.is_some() && !opts.dry_run {
+        usage_error("--wrap-width needs --dry-run");
+    }
+    if opts.max_capture.is_some() {
+        if !opts.merge_stderr && opts.merged_output.is_none() {
+            usage_error("--max-capture needs --merge-stderr or --merged-output");
+        }
+        if opts.output_prefix.is_some() || opts.response_file.is_some() {
+            usage_error("--max-capture can't be combined with --output-prefix or --response-file");
+        }
+    }
+    let env = std::env::var("PHARGS_PLACEHOLDER").ok();
+    if let Some(token) = placeholder_token(opts.placeholder.take(), env) {
+        if token.is_empty() {
+            usage_error("--placeholder can't be empty");
+        }
+        for arg in &mut opts.command {
+            *arg = replace_placeholder(arg, &token);
+        }
+    }
+
+    let (program, args) = if opts.shell {
+        shell_command(&default_shell(), &opts.command)
+    } else {
+        let mut command = std::mem::take(&mut opts.command);
+        let args = command.split_off(1);
+        (command.remove(0), args)
+    };
+    let program = if opts.sibling {
+        find_program_from_env(&program)
+    } else {
+        program
+    };
+
+    let mut commands = match read_phargs(&mut opts)? {
+        Phargs::List(phargs) => PhCommandVec::new(program, args, phargs),
+        Phargs::Lines(lines) => PhCommandVec::from_lines(program, args, lines),
+        Phargs::Named(lists) => PhCommandVec::named(program, args, lists),
+    };
+    if let Some(delimiter) = opts.field_delim {
+        commands = commands.field_delimiter(delimiter);
+    }
+    if let Some(delimiter) = opts.sub_delim.take() {
+        commands = commands.sub_delimiter(delimiter);
+    }
+    if let Some(template) = opts.stdin_template.take() {
+        commands = commands.stdin_template(template);
+    }
+    if opts.value_only {
+        commands = commands.value_only(true);
+    }
+    if opts.keep_empty_brackets {
+        commands = commands.keep_empty_brackets(true);
+    }
+    if opts.brace_sets {
+        commands = commands.brace_sets(true);
+    }
+    if let Some(arg0) = opts.arg0.take() {
+        commands = commands.arg0(arg0);
+    }
+    if opts.clear_env {
+        commands = commands.clear_env(std::mem::take(&mut opts.keep_env));
+    }
+    if let Some(base) = opts.base.take() {
+        commands = commands.base(base);
+    }
+    if let Some(path) = opts.path.take() {
+        commands = commands.path(path);
+    }
+    if let Some(script) = opts.pre.take() {
+        let (program, args) = shell_command(&default_shell(), &[script]);
+        commands = commands.pre(program, args);
+    }
+    if let Some(script) = opts.post.take() {
+        let (program, args) = shell_command(&default_shell(), &[script]);
+        commands = commands.post(program, args);
+    }
+    #[cfg(feature = "nice")]
+    if let Some(increment) = opts.nice {
+        if cfg!(not(unix)) {
+            eprintln!("phargs: --nice is ignored on this platform");
+        }
+        commands = commands.nice(increment);
+    }
+    if let Some(max_chars) = opts.max_chars {
+        commands = commands.max_chars(max_chars);
+    }
+    commands = commands
+        .counter(opts.count_start, opts.count_step)
+        .wrap(std::mem::take(&mut opts.wrap));
+    let stdio = stdio_mode(
+        opts.inherit_stdio,
+        opts.null_stdio,
+        opts.output_prefix.is_some(),
+    );
+    let mut commands = commands
+        .stdio(stdio)
+        .skip_header(opts.skip_header)
+        .sort(opts.sort)
+        .numeric_sort(opts.numeric_sort)
+        .dedup_commands(opts.dedup_commands)
+        .strict_metadata(opts.strict_metadata);
+    if let Some(Shard(i, k)) = opts.shard {
+        commands = commands.split_chunks(k).swap_remove(i - 1);
+    }
+    let mut checkpoint = match &opts.checkpoint {
+        Some(_) if !commands.args_has_placeholder() || opts.join.is_some() => {
+            usage_error("--checkpoint needs a command run once per argument, without --join")
+        }
+        Some(path) => Some(Checkpoint::open(path).map_err(|e| format!("{}: {}", path, e))?),
+        None => None,
+    };
+    if let Some(checkpoint) = &checkpoint {
+        commands = commands.retain(|ph| !checkpoint.contains(ph));
+    }
+    if opts.chdir_per_pharg {
+        if opts.join.is_some() {
+            usage_error("--chdir-per-pharg can't be combined with --join");
+        }
+        commands = chdir_per_pharg(commands);
+    }
+    if let Some(sep) = &opts.join {
+        commands = commands.join(sep);
+    }
+    if opts.list_commands {
+        print!("{}", format_listing(&commands));
+        return Ok(());
+    }
+
+    check_max_commands(commands.len(), opts.max_commands)?;
+    if opts.strict_metadata && !opts.check {
+        commands
+            .validate()
+            .map_err(|tokens| format!("unresolved placeholders: {}", tokens.join(" ")))?;
+    }
+    if opts.deny_warnings {
+        deny_warnings(&commands)?;
+    }
+
+    #[cfg(feature = "serde")]
+    if let Some(path) = &opts.plan {
+        let argvs = commands.iter().map(|c| c.argv()).collect::<Vec<_>>();
+        let file = std::fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        write_plan(std::io::BufWriter::new(file), &argvs)?;
+        return Ok(());
+    }
+
+    if opts.dry_run_diff {
+        for a in commands.iter() {
+            println!("{}", a.command_string_marked("<<", ">>"));
+        }
+        return Ok(());
+    }
+
+    if opts.show_sizes {
+        for a in commands.iter() {
+            println!("{}\t{}", argv_size(&a.argv()), a.command_string());
+        }
+        return Ok(());
+    }
+
+    if opts.check {
+        for a in commands.iter() {
             println!("{}", a.command_string());
+        }
+        let missing = commands.missing_programs();
+        for program in &missing {
+            eprintln!("program not found: {}", program);
+        }
+        let unresolved = commands.validate().err().unwrap_or_default();
+        for token in &unresolved {
+            eprintln!("unresolved placeholder: {}", token);
+        }
+        let ok = missing.is_empty() && unresolved.is_empty();
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    if opts.confirm && !opts.dry_run {
+        let strings = commands
+            .iter()
+            .map(|c| c.command_string())
+            .collect::<Vec<_>>();
+        eprint!("{}Run them? [y/N] ", confirm_preview(&strings));
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !is_confirmed(&answer) {
+            return Err("aborted".into());
+        }
+    }
+
+    if opts.announce_count {
+        eprintln!("{}", format_count(commands.len()));
+    }
+
+    if !opts.dry_run {
+        run_hook(commands.pre_command(), "pre-command")?;
+    }
+
+    let success_codes = opts.success_codes.take().map_or(vec![0], |codes| codes.0);
+    if opts.first_success && !opts.dry_run {
+        let success = commands.run_until_success_by(|c, result| match result {
+            Ok(status) => is_success(status.code(), &success_codes),
+            Err(e) => {
+                error!("failed to run: {}: {}", c.command_string(), e);
+                false
+            }
+        });
+        run_hook(commands.post_command(), "post-command")?;
+        return match success {
+            Some((command, _)) => {
+                info!("succeeded: {}", command);
+                Ok(())
+            }
+            None => {
+                error!("all commands failed");
+                std::process::exit(failure_exit_code(None, opts.fail_code));
+            }
+        };
+    }
+
+    let mut timings = Vec::new();
+    let mut report = Vec::new();
+    let mut results = Vec::new();
+    let mut failure = None;
+    let mut merged_output = match &opts.merged_output {
+        Some(path) if !opts.dry_run => {
+            Some(std::fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?)
+        }
+        _ => None,
+    };
+    #[cfg(unix)]
+    if opts.forward_signals {
+        forward_signals()?;
+    }
+    #[cfg(unix)]
+    let forwarding = opts.forward_signals;
+    let mut limiter = opts.rate.map(RateLimiter::new);
+    let mut jitter = opts.jitter.take().map(|JitterRange(min, max)| {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Jitter::new(min, max, seed ^ u64::from(std::process::id()))
+    });
+    for (a, command_string) in commands.iter_with_strings() {
+        if opts.dry_run {
+            match opts.wrap_width {
+                Some(width) => println!("{}", wrap_command(&a.argv(), width)),
+                None => println!("{}", command_string),
+            }
             continue;
         }
-        info!("running: {}", a.command_string());
-        let status = a.command().status()?;
-        if !status.success() {
-            error!("failed to run: {}", a.command_string());
-            std::process::exit(status.code().ok_or("exit code not found")?);
+        if let Some(jitter) = &mut jitter {
+            jitter.sleep();
+        }
+        if let Some(limiter) = &mut limiter {
+            limiter.wait();
+        }
+        info!("running: {}", command_string);
+        if opts.trace {
+            eprintln!("{}", format_start(&command_string));
+        }
+        #[cfg(feature = "serde")]
+        if opts.log_json {
+            let event = RunEvent::Start {
+                index: results.len() + 1,
+                command: &command_string,
+                ph: a.ph(),
+            };
+            eprintln!("{}", event.to_json());
+        }
+        let start = Instant::now();
+        let status = match (&opts.response_file, &opts.output_prefix) {
+            (Some(template), _) => {
+                let path = response_file_path(&a.expand(template), results.len() + 1)?;
+                let status = a
+                    .response_file_command(&path)
+                    .and_then(|mut c| a.spawn_with_input(&mut c)?.wait());
+                std::fs::remove_file(&path).ok();
+                status?
+            }
+            (None, Some(prefix)) => a.run_prefixed(prefix, std::io::stdout().lock())?,
+            (None, None) if merged_output.is_some() || opts.merge_stderr => {
+                let limit = opts.max_capture.unwrap_or(usize::MAX);
+                let (status, output, truncated) = if opts.merge_stderr {
+                    a.output_merged_limited(limit)?
+                } else {
+                    let (output, truncated) = match opts.max_capture {
+                        Some(limit) => a.output_limited(limit)?,
+                        None => (a.output()?, false),
+                    };
+                    std::io::Write::write_all(&mut std::io::stderr().lock(), &output.stderr)?;
+                    (output.status, output.stdout, truncated)
+                };
+                match merged_output.as_mut() {
+                    Some(file) => write_merged_section(file, &command_string, &output)?,
+                    None => std::io::Write::write_all(&mut std::io::stdout().lock(), &output)?,
+                }
+                if truncated {
+                    eprintln!(
+                        "phargs: output truncated at {} bytes: {}",
+                        limit, command_string
+                    );
+                }
+                status
+            }
+            #[cfg(unix)]
+            (None, None) if forwarding => {
+                wait_forwarding(&mut a.spawn_with_input(&mut a.command())?)?
+            }
+            (None, None) => a.status()?,
+        };
+        let elapsed = start.elapsed();
+        if opts.trace {
+            let done = format_done(&command_string, status.code(), elapsed);
+            eprintln!("{}", done);
+        }
+        #[cfg(feature = "serde")]
+        if opts.log_json {
+            let (index, command, ph, code) = (
+                results.len() + 1,
+                command_string.as_str(),
+                a.ph(),
+                status.code(),
+            );
+            let event = if is_success(code, &success_codes) {
+                RunEvent::Done {
+                    index,
+                    command,
+                    ph,
+                    code,
+                    duration: elapsed,
+                }
+            } else {
+                RunEvent::Fail {
+                    index,
+                    command,
+                    ph,
+                    code,
+                    duration: elapsed,
+                }
+            };
+            eprintln!("{}", event.to_json());
+        }
+        if opts.timings {
+            if opts.verbose {
+                eprintln!("{} {:.3}s", command_string, elapsed.as_secs_f64());
+            }
+            timings.push((command_string.clone(), elapsed));
         }
+        if opts.csv.is_some() {
+            report.push((command_string.clone(), status.code(), elapsed));
+        }
+        results.push(Ok(status));
+        #[cfg(unix)]
+        if let Some(signal) = received_signal().filter(|_| forwarding) {
+            error!("stopped by signal {}", signal);
+            failure = Some(Some(128 + signal));
+            break;
+        }
+        if !is_success(status.code(), &success_codes) {
+            error!("failed to run: {}", command_string);
+            failure = Some(status.code());
+            break;
+        }
+        if let Some(checkpoint) = &mut checkpoint {
+            checkpoint.record(a.ph())?;
+        }
+    }
+    if opts.timings {
+        eprintln!("{}", timing_summary(&timings));
+    }
+    if let Some(path) = &opts.csv {
+        let file = std::fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        write_csv_report(std::io::BufWriter::new(file), &report, &success_codes)?;
+    }
+    if !opts.dry_run {
+        if opts.verbose {
+            let summary = RunSummary::with_success(commands.len(), &results, |status| {
+                is_success(status.code(), &success_codes)
+            });
+            eprintln!("{}", summary);
+        }
+        run_hook(commands.post_command(), "post-command")?;
+    }
+    if let Some(code) = failure {
+        std::process::exit(failure_exit_code(code, opts.fail_code));
     }
 
     Ok(())
 }
+
+/// Keeps only the phargs that are directories, warning about the others, and
+/// runs each command in its pharg, for `--chdir-per-pharg`.
+fn chdir_per_pharg(commands: PhCommandVec) -> PhCommandVec {
+    commands
+        .retain(|ph| {
+            let is_dir = std::path::Path::new(ph).is_dir();
+            if !is_dir {
+                eprintln!("phargs: skipping {}: not a directory", ph);
+            }
+            is_dir
+        })
+        .current_dir("{}")
+}
+
+/// Returns the warnings about the templates of `commands`: `[` arguments
+/// without a closing `]`, and placeholders that don't resolve for a pharg.
+fn template_warnings(commands: &PhCommandVec) -> Vec<String> {
+    let mut warnings = commands
+        .template()
+        .iter()
+        .filter(|arg| arg.starts_with('[') && parse_bracket(arg).is_none())
+        .map(|arg| format!("unmatched bracket: {}", arg))
+        .collect::<Vec<_>>();
+    if let Err(tokens) = commands.validate() {
+        warnings.extend(
+            tokens
+                .into_iter()
+                .map(|token| format!("unresolved placeholder: {}", token)),
+        );
+    }
+    warnings
+}
+
+/// Fails with the [`template_warnings`] of `commands`, if any, for `--deny-warnings`.
+fn deny_warnings(commands: &PhCommandVec) -> Result<(), String> {
+    let warnings = template_warnings(commands);
+    if warnings.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("denied warnings: {}", warnings.join(", ")))
+    }
+}
+
+/// Formats the `--list-commands` output: the raw program and arguments, and
+/// the number of phargs.
+fn format_listing(commands: &PhCommandVec) -> String {
+    format!(
+        "program: {}\nargs: {}\nphargs: {}\n",
+        commands.program(),
+        commands.template().join(" "),
+        commands.phargs().len()
+    )
+}
+
+/// Returns the size of `argv` in bytes for `--show-sizes`: every element
+/// followed by its terminating NUL, as `--max-chars` counts it.
+fn argv_size(argv: &[String]) -> usize {
+    argv.iter().map(|arg| arg.len() + 1).sum()
+}
+
+/// Joins `argv` with spaces like a command string, but breaks it between
+/// words into lines of at most `width` columns for `--wrap-width`.
+///
+/// Each line but the last ends with ` \`, and continuation lines are indented
+/// by two spaces. A word too long for a line gets a line of its own.
+fn wrap_command(argv: &[String], width: usize) -> String {
+    let mut out = String::new();
+    let mut line = String::new();
+    for word in argv {
+        if !line.is_empty() && line.len() + 1 + word.len() + " \\".len() > width {
+            out.push_str(&line);
+            out.push_str(" \\\n");
+            line = "  ".to_string();
+        } else if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    out.push_str(&line);
+    out
+}
+
+/// Formats the `--announce-count` line for a batch of `count` commands.
+fn format_count(count: usize) -> String {
+    format!(
+        "phargs: {} command{}",
+        count,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+/// Formats the `--trace` line printed before running `command`.
+fn format_start(command: &str) -> String {
+    format!("start: {}", command)
+}
+
+/// Formats the `--trace` line printed after `command` exited with `code`.
+fn format_done(command: &str, code: Option<i32>, elapsed: Duration) -> String {
+    let code = match code {
+        Some(code) => format!("exit {}", code),
+        None => "killed".to_string(),
+    };
+    format!(
+        "done: {} ({}, {:.1}s)",
+        command,
+        code,
+        elapsed.as_secs_f64()
+    )
+}
+
+/// Writes the `--merged-output` section of `command`: a header line, then
+/// its `stdout`, ending with a line break.
+fn write_merged_section<W: std::io::Write>(
+    mut out: W,
+    command: &str,
+    stdout: &[u8],
+) -> std::io::Result<()> {
+    writeln!(out, "=== {} ===", command)?;
+    out.write_all(stdout)?;
+    if !stdout.is_empty() && !stdout.ends_with(b"\n") {
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// Quotes `field` for CSV if it contains a comma, quote or line break.
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Writes the `--csv` report, one row per `(command, exit code, duration)`.
+///
+/// The success column is decided by [`is_success`] with `success_codes`.
+fn write_csv_report<W: std::io::Write>(
+    mut out: W,
+    report: &[(String, Option<i32>, Duration)],
+    success_codes: &[i32],
+) -> std::io::Result<()> {
+    writeln!(out, "command,exit_code,duration,success")?;
+    for (command, code, duration) in report {
+        writeln!(
+            out,
+            "{},{},{:.3},{}",
+            csv_field(command),
+            code.map(|c| c.to_string()).unwrap_or_default(),
+            duration.as_secs_f64(),
+            is_success(*code, success_codes)
+        )?;
+    }
+    out.flush()
+}
+
+/// Formats `total=... slowest=<cmd> N.Ns` from per-command timings.
+fn timing_summary(timings: &[(String, Duration)]) -> String {
+    let total: Duration = timings.iter().map(|(_, d)| *d).sum();
+    match timings.iter().max_by_key(|(_, d)| *d) {
+        Some((cmd, d)) => format!(
+            "total={:.3}s slowest={} {:.3}s",
+            total.as_secs_f64(),
+            cmd,
+            d.as_secs_f64()
+        ),
+        None => format!("total={:.3}s", total.as_secs_f64()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_after_sentinel() {
+        let parse = |args: &[&str]| Args::from_args(&["phargs"], args).unwrap();
+
+        let opts = parse(&["-w", "a", "--", "ls", "-la", "{}"]);
+        assert_eq!(opts.wlist, Some(Xargs(vec!["a".to_string()])));
+        assert_eq!(opts.command, ["ls", "-la", "{}"]);
+
+        // Options after `--` belong to the command, not to phargs.
+        let opts = parse(&["-w", "a", "--", "-v", "--dry-run", "{}"]);
+        assert!(!opts.verbose && !opts.dry_run);
+        assert_eq!(opts.command, ["-v", "--dry-run", "{}"]);
+
+        // A later `--` is part of the command.
+        let opts = parse(&["-n", "-w", "a", "--", "rm", "--", "{}"]);
+        assert!(opts.dry_run);
+        assert_eq!(opts.command, ["rm", "--", "{}"]);
+        let opts = parse(&["-w", "a", "rm", "--", "-v"]);
+        assert!(!opts.verbose);
+        assert_eq!(opts.command, ["rm", "--", "-v"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_plan_conflict() {
+        let conflict = |args: &[&str]| plan_conflict(&Args::from_args(&["phargs"], args).unwrap());
+        assert_eq!(conflict(&["-w", "a", "echo"]), None);
+        assert_eq!(
+            conflict(&["--plan", "p", "-w", "a", "--wrap", "nice", "echo"]),
+            None
+        );
+        assert_eq!(
+            conflict(&["--plan", "p", "-w", "a", "--stdin-template", "{}", "cat"]),
+            Some("--stdin-template")
+        );
+        assert_eq!(
+            conflict(&["--plan", "p", "-w", "a", "--chdir-per-pharg", "ls"]),
+            Some("--chdir-per-pharg")
+        );
+        assert_eq!(
+            conflict(&["--apply", "p", "-n", "--success-codes", "0,1"]),
+            None
+        );
+        assert_eq!(conflict(&["--apply", "p", "--rate", "1"]), Some("--rate"));
+        assert_eq!(conflict(&["--apply", "p", "-v"]), Some("-v"));
+        assert_eq!(conflict(&["--apply", "p", "--pre", "true"]), Some("--pre"));
+        assert_eq!(conflict(&["--apply", "p", "-w", "a"]), Some("-w"));
+        assert_eq!(conflict(&["--apply", "p", "--plan", "q"]), Some("--plan"));
+        assert_eq!(conflict(&["--apply", "p", "echo"]), Some("a command"));
+    }
+
+    #[test]
+    fn test_first_success_conflict() {
+        let conflict =
+            |args: &[&str]| first_success_conflict(&Args::from_args(&["phargs"], args).unwrap());
+        assert_eq!(conflict(&["--first-success", "-w", "a", "true"]), None);
+        assert_eq!(
+            conflict(&["--first-success", "--csv", "r.csv", "-w", "a", "true"]),
+            Some("--csv")
+        );
+        assert_eq!(
+            conflict(&["--first-success", "--rate", "2", "-w", "a", "true"]),
+            Some("--rate")
+        );
+        assert_eq!(
+            conflict(&["--first-success", "--timings", "-w", "a", "true"]),
+            Some("--timings")
+        );
+    }
+
+    #[test]
+    fn test_failure_exit_code() {
+        assert_eq!(failure_exit_code(Some(3), None), 3);
+        assert_eq!(failure_exit_code(None, None), 1);
+        assert_eq!(failure_exit_code(Some(3), Some(42)), 42);
+        assert_eq!(failure_exit_code(None, Some(42)), 42);
+    }
+
+    #[test]
+    fn test_skip_empty_command() {
+        assert_eq!(skip_empty_command(false, false), Ok(false));
+        assert_eq!(skip_empty_command(false, true), Ok(false));
+        assert_eq!(skip_empty_command(true, true), Ok(true));
+        assert_eq!(skip_empty_command(true, false), Err("command is empty"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chdir_per_pharg() {
+        let root = std::env::temp_dir().join(format!("phargs-chdir-{}", std::process::id()));
+        let dirs = ["a", "b"].map(|s| root.join(s));
+        for dir in &dirs {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        let dirs = dirs.map(|dir| std::fs::canonicalize(dir).unwrap());
+        let phargs = vec![&dirs[0], &file, &dirs[1]]
+            .into_iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let commands = chdir_per_pharg(PhCommandVec::new("pwd", Vec::<String>::new(), phargs));
+        let outputs = commands
+            .iter()
+            .map(|c| String::from_utf8(c.output().unwrap().stdout).unwrap())
+            .collect::<Vec<_>>();
+        let expected = dirs.map(|dir| format!("{}\n", dir.display()));
+        assert_eq!(outputs, expected);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_phargs_from_env() {
+        let name = format!("PHARGS_TEST_TARGETS_{}", std::process::id());
+        assert_eq!(phargs_from_env(&name), Err(format!("${} is not set", name)));
+        std::env::set_var(&name, r"a,b\,c,d");
+        assert_eq!(phargs_from_env(&name).unwrap(), ["a", "b,c", "d"]);
+        std::env::remove_var(&name);
+    }
+
+    #[test]
+    fn test_write_merged_section() {
+        let commands = PhCommandVec::new("printf", vec!["%s", "{}"], vec!["a\nb\n", "c"]);
+        let mut merged = Vec::new();
+        for command in commands.iter() {
+            let output = command.output().unwrap();
+            write_merged_section(&mut merged, &command.command_string(), &output.stdout).unwrap();
+        }
+        write_merged_section(&mut merged, "true", b"").unwrap();
+        assert_eq!(
+            String::from_utf8(merged).unwrap(),
+            "=== printf %s a\nb\n ===\na\nb\n=== printf %s c ===\nc\n=== true ===\n"
+        );
+    }
+
+    #[test]
+    fn test_deny_warnings() {
+        let commands =
+            PhCommandVec::new("echo", vec!["{1}", "{2}"], vec!["a,b", "c"]).field_delimiter(",");
+        assert_eq!(
+            template_warnings(&commands),
+            ["unresolved placeholder: {2}"]
+        );
+        assert_eq!(
+            deny_warnings(&commands),
+            Err("denied warnings: unresolved placeholder: {2}".to_string())
+        );
+
+        let commands =
+            PhCommandVec::new("echo", vec!["{1}", "{2}"], vec!["a,b"]).field_delimiter(",");
+        assert_eq!(deny_warnings(&commands), Ok(()));
+
+        let commands = PhCommandVec::new("echo", vec!["[{}", "{}"], vec!["a"]);
+        assert_eq!(template_warnings(&commands), ["unmatched bracket: [{}"]);
+    }
+
+    #[test]
+    fn test_wrap_command() {
+        let argv = "rsync -av --exclude tmp src/dir-one src/dir-two dest"
+            .split(' ')
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            wrap_command(&argv, 24),
+            "rsync -av --exclude \\\n  tmp src/dir-one \\\n  src/dir-two dest"
+        );
+        assert!(wrap_command(&argv, 24).lines().all(|line| line.len() <= 24));
+        assert_eq!(wrap_command(&argv, 100), argv.join(" "));
+
+        // Words longer than the width still go on a line of their own.
+        let argv = ["echo", "a-very-long-word", "x"].map(String::from);
+        assert_eq!(
+            wrap_command(&argv, 8),
+            "echo \\\n  a-very-long-word \\\n  x"
+        );
+        assert_eq!(wrap_command(&[], 8), "");
+    }
+
+    #[test]
+    fn test_format_count() {
+        assert_eq!(format_count(42), "phargs: 42 commands");
+        assert_eq!(format_count(1), "phargs: 1 command");
+        assert_eq!(format_count(0), "phargs: 0 commands");
+    }
+
+    #[test]
+    fn test_argv_size() {
+        let commands = PhCommandVec::new("echo", vec!["-n", "[{}]"], vec!["a", "bc"]);
+        let argv = commands.iter().next().unwrap().argv();
+        assert_eq!(argv, ["echo", "-n", "a", "bc"]);
+        assert_eq!(argv_size(&argv), 4 + 2 + 1 + 2 + 4);
+        assert_eq!(argv_size(&[]), 0);
+    }
+
+    #[test]
+    fn test_check_max_commands() {
+        let commands = PhCommandVec::new("echo", vec!["{}"], vec!["1", "2", "3", "4", "5"]);
+        assert!(check_max_commands(commands.len(), Some(3)).is_err());
+        assert!(check_max_commands(commands.len(), Some(5)).is_ok());
+        assert!(check_max_commands(commands.len(), None).is_ok());
+    }
+
+    #[test]
+    fn test_confirm_preview() {
+        let commands = (1..=100).map(|i| format!("echo {}", i)).collect::<Vec<_>>();
+        assert_eq!(
+            confirm_preview(&commands),
+            "About to run 100 commands:\n  echo 1\n  echo 2\n  echo 3\n  ... 94 more\n  echo 98\n  echo 99\n  echo 100\n"
+        );
+        assert_eq!(
+            confirm_preview(&commands[..2]),
+            "About to run 2 commands:\n  echo 1\n  echo 2\n"
+        );
+        assert!(is_confirmed(" Y\n"));
+        assert!(!is_confirmed("\n"));
+    }
+
+    #[test]
+    fn test_placeholder_token() {
+        let s = |s: &str| Some(s.to_string());
+        assert_eq!(placeholder_token(s("@@"), s("%%")), s("@@"));
+        assert_eq!(placeholder_token(None, s("%%")), s("%%"));
+        assert_eq!(placeholder_token(None, s("")), None);
+        assert_eq!(placeholder_token(None, None), None);
+    }
+
+    #[test]
+    fn test_response_file_path() {
+        let path = response_file_path("args.rsp", 3).unwrap();
+        assert_eq!(path.parent(), Some(std::env::temp_dir().as_path()));
+        let name = format!("phargs-{}-3-args.rsp", std::process::id());
+        assert_eq!(path.file_name().unwrap(), name.as_str());
+        for name in ["/tmp/rt/victim", "../x", "a/b", "a/", "..", ".", "", r"a\b"] {
+            assert!(response_file_path(name, 1).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_replace_placeholder() {
+        assert_eq!(replace_placeholder("%", "%"), "{}");
+        assert_eq!(replace_placeholder("%.txt", "%"), "{}.txt");
+        assert_eq!(replace_placeholder("--in=%/%", "%"), "--in={}/{}");
+        assert_eq!(replace_placeholder("100%", "%"), "100%");
+        assert_eq!(replace_placeholder("100% of %", "%"), "100% of {}");
+        assert_eq!(replace_placeholder("@@@", "@@"), "{}@");
+    }
+
+    #[test]
+    fn test_shard() {
+        assert_eq!("2/4".parse(), Ok(Shard(2, 4)));
+        assert!("0/4".parse::<Shard>().is_err());
+        assert!("5/4".parse::<Shard>().is_err());
+        assert!("2".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn test_jitter_range() {
+        assert_eq!(
+            "0.5-2".parse(),
+            Ok(JitterRange(
+                Duration::from_millis(500),
+                Duration::from_secs(2)
+            ))
+        );
+        assert_eq!(
+            "0-0".parse(),
+            Ok(JitterRange(Duration::ZERO, Duration::ZERO))
+        );
+        assert!("2-1".parse::<JitterRange>().is_err());
+        assert!("1".parse::<JitterRange>().is_err());
+        assert!("a-1".parse::<JitterRange>().is_err());
+    }
+
+    #[test]
+    fn test_format_listing() {
+        let commands = PhCommandVec::new("cp", vec!["{}", "[{}.bak]", "{#}"], vec!["a", "b"]);
+        assert_eq!(
+            format_listing(&commands),
+            "program: cp\nargs: {} [{}.bak] {#}\nphargs: 2\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_lines() {
+        assert_eq!(format_start("echo a"), "start: echo a");
+        assert_eq!(
+            format_done("echo a", Some(1), Duration::from_millis(1234)),
+            "done: echo a (exit 1, 1.2s)"
+        );
+        assert_eq!(
+            format_done("sleep 9", None, Duration::ZERO),
+            "done: sleep 9 (killed, 0.0s)"
+        );
+    }
+
+    /// Parses the CSV written by [`write_csv_report`].
+    fn parse_csv(csv: &str) -> Vec<Vec<String>> {
+        let mut rows = vec![vec![String::new()]];
+        let (mut quoted, mut chars) = (false, csv.chars().peekable());
+        while let Some(c) = chars.next() {
+            let field = rows.last_mut().unwrap().last_mut().unwrap();
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => rows.last_mut().unwrap().push(String::new()),
+                '\n' if !quoted => rows.push(vec![String::new()]),
+                c => field.push(c),
+            }
+        }
+        rows.pop();
+        rows
+    }
+
+    #[test]
+    fn test_csv_report() {
+        let report = [
+            ("echo a".to_string(), Some(0), Duration::from_millis(1500)),
+            ("echo \"a,b\"\nc".to_string(), Some(2), Duration::ZERO),
+            ("sleep 9".to_string(), None, Duration::ZERO),
+        ];
+        let mut out = Vec::new();
+        write_csv_report(&mut out, &report, &[0]).unwrap();
+        let rows = parse_csv(&String::from_utf8(out).unwrap());
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], ["command", "exit_code", "duration", "success"]);
+        assert_eq!(rows[1], ["echo a", "0", "1.500", "true"]);
+        assert_eq!(rows[2], ["echo \"a,b\"\nc", "2", "0.000", "false"]);
+        assert_eq!(rows[3], ["sleep 9", "", "0.000", "false"]);
+    }
+
+    #[test]
+    fn test_is_success() {
+        assert!(is_success(Some(0), &[0]));
+        assert!(!is_success(Some(1), &[0]));
+        assert!(!is_success(None, &[0]));
+        let codes = "0,1".parse::<Codes>().unwrap();
+        assert!(is_success(Some(1), &codes.0));
+        assert!(!is_success(Some(2), &codes.0));
+        assert!("0,x".parse::<Codes>().is_err());
+    }
+
+    #[test]
+    fn test_named_list() {
+        assert_eq!(
+            "src=a,b".parse(),
+            Ok(NamedList(
+                "src".to_string(),
+                vec!["a".to_string(), "b".to_string()]
+            ))
+        );
+        assert!("src".parse::<NamedList>().is_err());
+    }
+
+    #[test]
+    fn test_stdio_mode() {
+        assert_eq!(stdio_mode(false, false, false), StdioMode::Inherit);
+        assert_eq!(stdio_mode(false, false, true), StdioMode::Default);
+        assert_eq!(stdio_mode(true, false, true), StdioMode::Inherit);
+        assert_eq!(stdio_mode(false, true, false), StdioMode::Null);
+        assert_eq!(stdio_mode(true, true, false), StdioMode::Null);
+    }
+}
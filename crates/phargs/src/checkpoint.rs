@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// An append-only record of completed phargs, for resuming a batch.
+///
+/// The file holds one pharg per line, so phargs containing line breaks can't
+/// be recorded faithfully.
+///
+/// # Examples
+///
+/// ```
+/// let path = std::env::temp_dir().join(format!("phargs-doc-checkpoint-{}", std::process::id()));
+/// let mut checkpoint = phargs::Checkpoint::open(&path).unwrap();
+/// checkpoint.record("a").unwrap();
+///
+/// let checkpoint = phargs::Checkpoint::open(&path).unwrap();
+/// assert!(checkpoint.contains("a"));
+/// assert!(!checkpoint.contains("b"));
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Checkpoint {
+    done: HashSet<String>,
+    file: File,
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint at `path`, creating it if it doesn't exist, and
+    /// reads the phargs completed so far.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let done = io::BufReader::new(&mut file)
+            .lines()
+            .collect::<io::Result<_>>()?;
+        Ok(Checkpoint { done, file })
+    }
+
+    /// Returns true if `ph` was recorded as completed.
+    pub fn contains(&self, ph: &str) -> bool {
+        self.done.contains(ph)
+    }
+
+    /// Records `ph` as completed, writing it to the file right away.
+    pub fn record(&mut self, ph: &str) -> io::Result<()> {
+        writeln!(self.file, "{}", ph)?;
+        self.file.flush()?;
+        self.done.insert(ph.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let path = std::env::temp_dir().join(format!("phargs-checkpoint-{}", std::process::id()));
+        let phargs = vec!["a", "b", "c", "d"];
+
+        let mut checkpoint = Checkpoint::open(&path).unwrap();
+        let pcv = crate::PhCommandVec::new("true", vec!["{}"], phargs.clone());
+        for command in pcv.iter().take(2) {
+            assert!(command.status().unwrap().success());
+            checkpoint.record(command.ph()).unwrap();
+        }
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        let pcv = crate::PhCommandVec::new("echo", vec!["{}"], phargs)
+            .retain(|ph| !checkpoint.contains(ph));
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo c", "echo d"]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\nb\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
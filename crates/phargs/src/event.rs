@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+/// An event of a batch run, logged as a JSON object per line.
+///
+/// # Examples
+///
+/// ```
+/// let event = phargs::RunEvent::Start { index: 1, command: "echo a", ph: "a" };
+/// assert_eq!(
+///     event.to_json(),
+///     r#"{"command":"echo a","event":"start","index":1,"pharg":"a"}"#
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunEvent<'a> {
+    /// The command with the 1-based sequence number `index` is about to run.
+    Start {
+        index: usize,
+        command: &'a str,
+        ph: &'a str,
+    },
+    /// The command exited successfully.
+    Done {
+        index: usize,
+        command: &'a str,
+        ph: &'a str,
+        code: Option<i32>,
+        duration: Duration,
+    },
+    /// The command failed, or was killed if `code` is `None`.
+    Fail {
+        index: usize,
+        command: &'a str,
+        ph: &'a str,
+        code: Option<i32>,
+        duration: Duration,
+    },
+}
+
+impl RunEvent<'_> {
+    /// Serializes the event as a single-line JSON object.
+    ///
+    /// Every event has `event`, `index`, `command` and `pharg`; `done` and
+    /// `fail` add `code` (`null` if killed) and `duration` in seconds.
+    pub fn to_json(&self) -> String {
+        let value = match *self {
+            RunEvent::Start { index, command, ph } => serde_json::json!({
+                "event": "start",
+                "index": index,
+                "command": command,
+                "pharg": ph,
+            }),
+            RunEvent::Done {
+                index,
+                command,
+                ph,
+                code,
+                duration,
+            }
+            | RunEvent::Fail {
+                index,
+                command,
+                ph,
+                code,
+                duration,
+            } => serde_json::json!({
+                "event": if matches!(self, RunEvent::Done { .. }) { "done" } else { "fail" },
+                "index": index,
+                "command": command,
+                "pharg": ph,
+                "code": code,
+                "duration": duration.as_secs_f64(),
+            }),
+        };
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_event_json() {
+        let start = RunEvent::Start {
+            index: 2,
+            command: "echo \"a b\"",
+            ph: "a b",
+        };
+        let value: serde_json::Value = serde_json::from_str(&start.to_json()).unwrap();
+        assert_eq!(value["event"], "start");
+        assert_eq!(value["index"], 2);
+        assert_eq!(value["command"], "echo \"a b\"");
+        assert_eq!(value["pharg"], "a b");
+
+        let fail = RunEvent::Fail {
+            index: 2,
+            command: "false",
+            ph: "",
+            code: None,
+            duration: Duration::from_millis(1500),
+        };
+        let value: serde_json::Value = serde_json::from_str(&fail.to_json()).unwrap();
+        assert_eq!(value["event"], "fail");
+        assert!(value["code"].is_null());
+        assert_eq!(value["duration"], 1.5);
+        assert!(!fail.to_json().contains('\n'));
+    }
+}
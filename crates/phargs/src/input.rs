@@ -0,0 +1,174 @@
+/// Reads phargs line by line, numbering them from 1.
+///
+/// Line terminators (`\n` or `\r\n`) are stripped.
+///
+/// # Examples
+///
+/// ```
+/// let lines = phargs::read_lines("a\nb\r\n".as_bytes()).unwrap();
+/// assert_eq!(lines, [(1, "a".to_string()), (2, "b".to_string())]);
+/// ```
+pub fn read_lines<R: std::io::BufRead>(reader: R) -> std::io::Result<Vec<(usize, String)>> {
+    reader
+        .lines()
+        .enumerate()
+        .map(|(i, line)| line.map(|line| (i + 1, line)))
+        .collect()
+}
+
+/// Reads phargs separated by runs of whitespace, like xargs does.
+///
+/// Single or double quotes group text containing whitespace into one pharg;
+/// the quotes themselves are removed.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` error if a quote is not closed, or if the input
+/// is not valid UTF-8.
+///
+/// # Examples
+///
+/// ```
+/// let phargs = phargs::read_words("a 'b c'\n d".as_bytes()).unwrap();
+/// assert_eq!(phargs, ["a", "b c", "d"]);
+/// ```
+pub fn read_words<R: std::io::Read>(mut reader: R) -> std::io::Result<Vec<String>> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input)?;
+
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in input.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unterminated quote",
+        ));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Runs `command` and reads phargs from its stdout.
+///
+/// The output is split by NUL bytes if it contains any, like that of
+/// `find -print0`, and by lines otherwise. A trailing separator doesn't
+/// produce an empty pharg.
+///
+/// # Errors
+///
+/// Returns an error if the command can't be run, fails, or prints
+/// something that isn't valid UTF-8.
+pub fn read_command_output(mut command: std::process::Command) -> std::io::Result<Vec<String>> {
+    let output = command.stderr(std::process::Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "producer command failed: {}",
+            output.status
+        )));
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let phargs = if stdout.contains('\0') {
+        stdout.split_terminator('\0').map(str::to_string).collect()
+    } else {
+        stdout.lines().map(str::to_string).collect()
+    };
+    Ok(phargs)
+}
+
+/// Reads phargs from a JSON array of strings.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` error if the input is not valid JSON, or not an
+/// array containing only strings.
+///
+/// # Examples
+///
+/// ```
+/// let phargs = phargs::read_json_array(r#"["a", "b"]"#.as_bytes()).unwrap();
+/// assert_eq!(phargs, ["a", "b"]);
+/// assert!(phargs::read_json_array(r#"["a", 1]"#.as_bytes()).is_err());
+/// ```
+#[cfg(feature = "serde")]
+pub fn read_json_array<R: std::io::Read>(reader: R) -> std::io::Result<Vec<String>> {
+    serde_json::from_reader(reader).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("expected a JSON array of strings: {}", e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_lines() {
+        let lines = read_lines("a\nb\n\nc".as_bytes()).unwrap();
+        assert_eq!(lines.len(), 4);
+        let lines = lines.into_iter().filter(|(_, l)| !l.is_empty()).collect();
+        let pcv = crate::PhCommandVec::from_lines("echo", vec!["{.line}:{}"], lines);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo 1:a", "echo 2:b", "echo 4:c"]);
+
+        let pcv = crate::PhCommandVec::new("echo", vec!["{.line}"], vec!["a", "b"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo {.line}"]);
+    }
+
+    #[test]
+    fn test_read_words() {
+        assert_eq!(
+            read_words("a b\tc\nd".as_bytes()).unwrap(),
+            ["a", "b", "c", "d"]
+        );
+        assert_eq!(
+            read_words("  a  \"b c\" '' \n".as_bytes()).unwrap(),
+            ["a", "b c", ""]
+        );
+        assert!(read_words("'a".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_read_command_output() {
+        let mut command = std::process::Command::new("printf");
+        command.arg("a\\nb\\n");
+        assert_eq!(read_command_output(command).unwrap(), ["a", "b"]);
+
+        let mut command = std::process::Command::new("printf");
+        command.arg("a b\\0c\\0");
+        assert_eq!(read_command_output(command).unwrap(), ["a b", "c"]);
+
+        let command = std::process::Command::new("false");
+        assert!(read_command_output(command).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_read_json_array() {
+        let phargs = read_json_array(r#"["a","b","c"]"#.as_bytes()).unwrap();
+        let pcv = crate::PhCommandVec::new("echo", vec!["{}"], phargs);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo a", "echo b", "echo c"]);
+
+        let err = read_json_array(r#"["a", 1]"#.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("expected a JSON array of strings"));
+        assert!(read_json_array(r#"{"a": "b"}"#.as_bytes()).is_err());
+    }
+}
@@ -1,3 +1,30 @@
+mod checkpoint;
+#[cfg(feature = "serde")]
+mod event;
+mod input;
+mod os;
+#[cfg(feature = "serde")]
+mod plan;
+mod run;
+#[cfg(unix)]
+mod signals;
+
+pub use checkpoint::Checkpoint;
+#[cfg(feature = "serde")]
+pub use event::RunEvent;
+#[cfg(feature = "serde")]
+pub use input::read_json_array;
+pub use input::{read_command_output, read_lines, read_words};
+pub use os::{substitute_os, PhOsCommand, PhOsCommandVec};
+#[cfg(feature = "serde")]
+pub use plan::{read_plan, write_plan};
+pub use run::{
+    run_argvs, run_argvs_summary, Jitter, RateLimiter, RunOptions, RunSummary, Semaphore,
+    SemaphorePermit,
+};
+#[cfg(unix)]
+pub use signals::{forward_signals, received_signal, wait_forwarding};
+
 /// Splits a string by commas and collects the results into a `Vec<String>`.
 ///
 /// # Examples
@@ -8,7 +35,71 @@
 /// assert_eq!(words, vec!["one".to_string(), "two".to_string(), "three".to_string()]);
 /// ```
 pub fn comma_separated(s: &str) -> Vec<String> {
-    s.split(',').map(|s| s.to_string()).collect()
+    CommaSeparated::new(s)
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// The comma separated fields of a string, borrowed instead of copied.
+///
+/// This is the zero-copy counterpart of [`comma_separated`].
+///
+/// # Examples
+///
+/// ```
+/// let fields = phargs::CommaSeparated::new("one,two");
+/// assert_eq!(fields.into_iter().collect::<Vec<_>>(), ["one", "two"]);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommaSeparated<'a>(&'a str);
+
+impl<'a> CommaSeparated<'a> {
+    pub fn new(s: &'a str) -> Self {
+        CommaSeparated(s)
+    }
+}
+
+impl<'a> IntoIterator for CommaSeparated<'a> {
+    type Item = &'a str;
+    type IntoIter = std::str::Split<'a, char>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.split(',')
+    }
+}
+
+/// Splits a string by commas like [`comma_separated`], honoring backslash escapes.
+///
+/// `\,` is a literal comma and `\\` a literal backslash. A backslash before
+/// any other character, or at the end of the string, is kept as is.
+///
+/// # Examples
+///
+/// ```
+/// let words = phargs::comma_separated_escaped(r"a\,b,c\\");
+/// assert_eq!(words, vec!["a,b".to_string(), r"c\".to_string()]);
+/// ```
+pub fn comma_separated_escaped(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ (',' | '\\')) => word.push(c),
+                Some(c) => {
+                    word.push('\\');
+                    word.push(c);
+                }
+                None => word.push('\\'),
+            },
+            ',' => words.push(std::mem::take(&mut word)),
+            c => word.push(c),
+        }
+    }
+    words.push(word);
+    words
 }
 
 /// Constructs a program path from the first argument to the current process.
@@ -30,15 +121,14 @@ pub fn comma_separated(s: &str) -> Vec<String> {
 /// assert_eq!(full_path, "/usr/bin/rustc");
 /// ```
 pub fn program_from_arg0(program: &str, arg0: &str) -> String {
-    arg0.rsplitn(2, '/')
-        .nth(1)
-        .map(|s| format!("{}/{}", s, program))
+    arg0.rsplit_once('/')
+        .map(|(s, _)| format!("{}/{}", s, program))
         .unwrap_or(program.to_string())
 }
 
 /// Finds the executable path in the environment.
 ///
-/// If the path exists, it returns the constructed path, otherwise just the program name.
+/// This is the same as [`resolve_program`].
 ///
 /// # Arguments
 ///
@@ -55,7 +145,50 @@ pub fn program_from_arg0(program: &str, arg0: &str) -> String {
 /// println!("Found program: {}", found_program);
 /// ```
 pub fn find_program_from_env(program: &str) -> String {
-    let p = program_from_arg0(program, &std::env::args().next().unwrap());
+    resolve_program(program)
+}
+
+/// Resolves the path of `program` to run.
+///
+/// This tries the directory of the current executable as in
+/// [`find_program_from_arg0`], then `PATH` as in [`which`], and falls back to
+/// `program` itself.
+///
+/// # Examples
+///
+/// ```
+/// assert_ne!(phargs::resolve_program("sh"), "sh");
+/// assert_eq!(phargs::resolve_program("no-such-program-for-phargs"), "no-such-program-for-phargs");
+/// ```
+pub fn resolve_program(program: &str) -> String {
+    let arg0 = std::env::args().next().unwrap_or_default();
+    resolve_program_in(program, &arg0, std::env::var_os("PATH").as_deref())
+}
+
+/// Same as [`resolve_program`], but with an explicit `arg0` and `PATH`.
+fn resolve_program_in(program: &str, arg0: &str, paths: Option<&std::ffi::OsStr>) -> String {
+    let sibling = program_from_arg0(program, arg0);
+    if std::path::Path::new(&sibling).exists() {
+        return sibling;
+    }
+    paths
+        .and_then(|paths| which_in(program, paths))
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|| program.to_string())
+}
+
+/// Finds the executable path next to `arg0`.
+///
+/// Same as [`find_program_from_env`], but with an explicit `arg0`.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(phargs::find_program_from_arg0("sh", "/bin/phargs"), "/bin/sh");
+/// assert_eq!(phargs::find_program_from_arg0("sh", "/no/such/dir/phargs"), "sh");
+/// ```
+pub fn find_program_from_arg0(program: &str, arg0: &str) -> String {
+    let p = program_from_arg0(program, arg0);
     if std::path::Path::new(&p).exists() {
         p
     } else {
@@ -63,32 +196,158 @@ pub fn find_program_from_env(program: &str) -> String {
     }
 }
 
+/// Returns the shell used to run commands in shell mode.
+///
+/// This is `$SHELL`, falling back to `sh`. On Windows it is `%COMSPEC%`,
+/// falling back to `cmd.exe`.
+pub fn default_shell() -> String {
+    #[cfg(windows)]
+    let (var, fallback) = ("COMSPEC", "cmd.exe");
+    #[cfg(not(windows))]
+    let (var, fallback) = ("SHELL", "sh");
+    std::env::var(var)
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+/// Builds the program and arguments that run `command` as a script of `shell`.
+///
+/// The words of `command` are joined with spaces into the script. An empty
+/// `command` runs each pharg itself as the script.
+///
+/// # Examples
+///
+/// ```
+/// # #[cfg(unix)] {
+/// let (program, args) = phargs::shell_command("sh", &["echo".to_string(), "{}".to_string()]);
+/// assert_eq!(program, "sh");
+/// assert_eq!(args, vec!["-c", "echo {}"]);
+/// # }
+/// ```
+pub fn shell_command(shell: &str, command: &[String]) -> (String, Vec<String>) {
+    #[cfg(windows)]
+    let flag = "/C";
+    #[cfg(not(windows))]
+    let flag = "-c";
+    let script = if command.is_empty() {
+        "{}".to_string()
+    } else {
+        command.join(" ")
+    };
+    (shell.to_string(), vec![flag.to_string(), script])
+}
+
+/// Searches `PATH` for an executable named `program`.
+///
+/// A `program` containing a path separator is checked as is instead.
+///
+/// # Returns
+///
+/// Returns the path of the executable, or `None` if it can't be found.
+///
+/// # Examples
+///
+/// ```
+/// assert!(phargs::which("sh").is_some());
+/// assert!(phargs::which("no-such-program-for-phargs").is_none());
+/// ```
+pub fn which(program: &str) -> Option<std::path::PathBuf> {
+    which_in(program, &std::env::var_os("PATH")?)
+}
+
+/// Same as [`which`], but searches `paths` instead of `PATH`.
+fn which_in(program: &str, paths: &std::ffi::OsStr) -> Option<std::path::PathBuf> {
+    let path = std::path::Path::new(program);
+    if program.contains(std::path::MAIN_SEPARATOR) || program.contains('/') {
+        return is_executable(path).then(|| path.to_path_buf());
+    }
+    std::env::split_paths(paths)
+        .map(|dir| dir.join(program))
+        .find(|p| is_executable(p))
+}
+
+fn is_executable(path: &std::path::Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
 /// A command with placeholders.
 ///
 /// This struct represents a command that may include placeholders (`{}`) for dynamic substitution.
+/// Both the program and its arguments are templates.
 pub struct PhCommand<'p, 'a> {
     program: &'p str,
-    args: &'a [String],
+    args: &'a [Arg],
     ph: String,
+    seq: usize,
+    row: &'a [String],
+    line: Option<usize>,
+    config: &'p Config,
 }
 
 impl PhCommand<'_, '_> {
+    fn context(&self) -> Context<'_> {
+        Context {
+            row: self.row,
+            line: self.line,
+            ..self.config.context(&self.ph, Some(self.seq))
+        }
+    }
+
+    /// Returns the pharg the command was created for; empty for `[..]` chunks.
+    pub fn ph(&self) -> &str {
+        &self.ph
+    }
+
+    /// Substitutes the placeholders of `template` as they are for this command.
+    ///
+    /// In addition to those of [`substitute`], `{#}` is replaced with the
+    /// counter of the command, which is its 1-based sequence number unless
+    /// changed with [`PhCommandVec::counter`].
+    pub fn expand(&self, template: &str) -> String {
+        Context {
+            value_only: false,
+            ..self.context()
+        }
+        .substitute(template)
+    }
+
     pub fn program(&self) -> &str {
         self.program
     }
 
+    /// Returns the program with placeholders substituted.
+    pub fn expanded_program(&self) -> String {
+        self.expand(self.program)
+    }
+
     /// Returns a new Vec of arguments with placeholders substituted.
     ///
-    /// This method substitutes any occurrence of `{}` in the arguments with `ph`.
+    /// This method substitutes every placeholder in the arguments with `ph`.
+    /// See [`substitute`] for the supported placeholders.
     ///
     /// # Returns
     ///
     /// Returns a `Vec<String>` with all placeholders substituted.
     pub fn args(&self) -> Vec<String> {
-        self.args
-            .iter()
-            .map(|s| s.replace("{}", &self.ph))
-            .collect()
+        let ctx = self.context();
+        self.args.iter().map(|arg| ctx.assemble(arg).0).collect()
+    }
+
+    /// Returns how many placeholders are substituted when producing [`args`](Self::args).
+    pub fn substitution_count(&self) -> usize {
+        let ctx = self.context();
+        self.args.iter().map(|arg| ctx.assemble(arg).1).sum()
     }
 
     /// Creates a `Command` ready to execute.
@@ -97,254 +356,4161 @@ impl PhCommand<'_, '_> {
     ///
     /// Returns a `std::process::Command` with arguments ready to be executed.
     pub fn command(&self) -> std::process::Command {
-        let mut command = std::process::Command::new(self.program);
-        command.args(&self.args());
+        let argv = self.argv();
+        let mut command = std::process::Command::new(&argv[0]);
+        command.args(&argv[1..]);
+        self.apply(&mut command);
         command
     }
 
+    /// Applies the settings of the batch to `command`, including its working
+    /// directory.
+    fn apply(&self, command: &mut std::process::Command) {
+        self.config.apply(command);
+        if let Some(dir) = &self.config.current_dir {
+            command.current_dir(self.expand(dir));
+        }
+    }
+
+    /// Writes the arguments to the response file `path` and creates a `Command`
+    /// passing `@path` in their place, for programs that read their arguments
+    /// from such a file.
+    ///
+    /// Each argument is written on its own line, in double quotes if it
+    /// contains whitespace or quotes. Removing the file is up to the caller.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `path` already exists, so that an existing file or a planted
+    /// symlink is never written through.
+    pub fn response_file_command(
+        &self,
+        path: &std::path::Path,
+    ) -> std::io::Result<std::process::Command> {
+        let mut contents = String::new();
+        for arg in self.args() {
+            if arg.contains(|c: char| c.is_whitespace() || c == '"') {
+                contents.push_str(&format!("\"{}\"", arg.replace('"', "\\\"")));
+            } else {
+                contents.push_str(&arg);
+            }
+            contents.push('\n');
+        }
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, contents.as_bytes()))?;
+
+        let mut program = self.config.wrapper.clone();
+        program.push(self.expanded_program());
+        let mut command = std::process::Command::new(&program[0]);
+        command.args(&program[1..]);
+        command.arg(format!("@{}", path.display()));
+        self.apply(&mut command);
+        Ok(command)
+    }
+
+    /// Returns the full argv of the command, starting with the wrapper
+    /// programs set with [`PhCommandVec::wrap`], if any.
+    pub fn argv(&self) -> Vec<String> {
+        let mut argv = self.config.wrapper.clone();
+        argv.push(self.expanded_program());
+        argv.extend(self.args());
+        argv
+    }
+
+    /// Creates the `Command` that the output of this command is piped into, if
+    /// one was set with [`PhCommandVec::pipe_to`].
+    pub fn pipe_command(&self) -> Option<std::process::Command> {
+        let (program, args) = self.config.pipe.as_ref()?;
+        let mut command = std::process::Command::new(self.expand(program));
+        command.args(args.iter().map(|s| self.expand(s)));
+        self.config.apply_env(&mut command);
+        Some(command)
+    }
+
+    /// Spawns `command` with [`spawn_with_input`](Self::spawn_with_input),
+    /// piping its stdout into the [`pipe_command`](Self::pipe_command) if there
+    /// is one. `setup` connects the streams of the final command.
+    fn spawn_pipeline(
+        &self,
+        mut command: std::process::Command,
+        setup: impl FnOnce(&mut std::process::Command),
+    ) -> std::io::Result<Spawned> {
+        let Some(mut next) = self.pipe_command() else {
+            setup(&mut command);
+            let last = self.spawn_with_input(&mut command)?;
+            return Ok(Spawned { last, first: None });
+        };
+        setup(&mut next);
+        let mut first = self.spawn_with_input(command.stdout(std::process::Stdio::piped()))?;
+        let stdout = first.stdout.take().expect("piped stdout");
+        match next.stdin(stdout).spawn() {
+            Ok(last) => Ok(Spawned {
+                last,
+                first: Some(first),
+            }),
+            Err(e) => {
+                let _ = first.kill();
+                let _ = first.wait();
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the stdin of the command, if a template was set with
+    /// [`PhCommandVec::stdin_template`].
+    pub fn stdin_input(&self) -> Option<String> {
+        let template = self.config.stdin_template.as_deref()?;
+        Some(self.expand(template))
+    }
+
+    /// Spawns `command`, writing the [`stdin_input`](Self::stdin_input) to
+    /// its stdin and closing it, if there is one.
+    ///
+    /// `command` is usually created by [`command`](Self::command) or
+    /// [`response_file_command`](Self::response_file_command). A pipe set with
+    /// [`PhCommandVec::pipe_to`] is not started.
+    pub fn spawn_with_input(
+        &self,
+        command: &mut std::process::Command,
+    ) -> std::io::Result<std::process::Child> {
+        let Some(input) = self.stdin_input() else {
+            return command.spawn();
+        };
+        let mut child = command.stdin(std::process::Stdio::piped()).spawn()?;
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        // Write from another thread, so that a child which doesn't read all of
+        // its stdin, or fills its stdout first, can't block us.
+        std::thread::spawn(move || std::io::Write::write_all(&mut stdin, input.as_bytes()));
+        Ok(child)
+    }
+
+    /// Runs the command and waits for it to finish.
+    ///
+    /// If a pipe was set with [`PhCommandVec::pipe_to`], the output is piped
+    /// into that command, whose status is returned.
+    pub fn status(&self) -> std::io::Result<std::process::ExitStatus> {
+        self.spawn_pipeline(self.command(), |_| {})?.wait()
+    }
+
+    /// Runs the command and collects its output.
+    ///
+    /// If a pipe was set with [`PhCommandVec::pipe_to`], the output of the
+    /// final command of the pipe is returned.
+    pub fn output(&self) -> std::io::Result<std::process::Output> {
+        self.spawn_pipeline(self.command(), Spawned::capture)?
+            .wait_with_output()
+    }
+
+    /// Runs the command and captures its stdout and stderr interleaved, in
+    /// the order they were written.
+    ///
+    /// Both streams are connected to the same pipe, so unlike
+    /// [`output`](Self::output) they can't be told apart. With a pipe, those
+    /// of its final command are captured.
+    pub fn output_merged(&self) -> std::io::Result<(std::process::ExitStatus, Vec<u8>)> {
+        let (status, output, _) = self.output_merged_limited(usize::MAX)?;
+        Ok((status, output))
+    }
+
+    /// Same as [`output_merged`](Self::output_merged), but keeps at most
+    /// `limit` bytes of the output and discards the rest.
+    ///
+    /// # Returns
+    ///
+    /// Returns the status, the output and whether the output was truncated.
+    pub fn output_merged_limited(
+        &self,
+        limit: usize,
+    ) -> std::io::Result<(std::process::ExitStatus, Vec<u8>, bool)> {
+        let (mut reader, writer) = std::io::pipe()?;
+        let stdout = writer.try_clone()?;
+        // The write ends are dropped with the command once it is spawned, so
+        // that reading stops when the child exits.
+        let spawned = self.spawn_pipeline(self.command(), |command| {
+            command.stdout(stdout).stderr(writer);
+        })?;
+        let mut output = Vec::new();
+        let truncated = read_limited(&mut reader, limit, &mut output)?;
+        Ok((spawned.wait()?, output, truncated))
+    }
+
+    /// Same as [`output`](Self::output), but keeps at most `limit` bytes of
+    /// each of stdout and stderr and discards the rest.
+    ///
+    /// # Returns
+    ///
+    /// Returns the output and whether stdout or stderr was truncated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("echo", vec!["{}"], vec!["hello"]);
+    /// let (output, truncated) = pcv.iter().next().unwrap().output_limited(4).unwrap();
+    /// assert_eq!(output.stdout, b"hell");
+    /// assert!(truncated);
+    /// ```
+    pub fn output_limited(&self, limit: usize) -> std::io::Result<(std::process::Output, bool)> {
+        let mut spawned = self.spawn_pipeline(self.command(), Spawned::capture)?;
+        let mut stdout = spawned.last.stdout.take().expect("piped stdout");
+        let mut stderr = spawned.last.stderr.take().expect("piped stderr");
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let truncated = std::thread::scope(|s| {
+            let stderr = s.spawn(|| read_limited(&mut stderr, limit, &mut err));
+            let out_truncated = read_limited(&mut stdout, limit, &mut out)?;
+            let err_truncated = stderr.join().expect("stderr thread panicked")?;
+            Ok::<_, std::io::Error>(out_truncated || err_truncated)
+        })?;
+        let output = std::process::Output {
+            status: spawned.wait()?,
+            stdout: out,
+            stderr: err,
+        };
+        Ok((output, truncated))
+    }
+
+    /// Runs the command, streaming its stdout to `out` and stderr to `err`.
+    ///
+    /// Output is copied as it is produced, so memory use stays bounded no
+    /// matter how much the command writes.
+    pub fn stream_output<W: std::io::Write + Send>(
+        &self,
+        mut out: W,
+        mut err: W,
+    ) -> std::io::Result<std::process::ExitStatus> {
+        let mut spawned = self.spawn_pipeline(self.command(), Spawned::capture)?;
+        let mut stdout = spawned.last.stdout.take().expect("piped stdout");
+        let mut stderr = spawned.last.stderr.take().expect("piped stderr");
+        std::thread::scope(|s| {
+            let stderr = s.spawn(move || std::io::copy(&mut stderr, &mut err));
+            std::io::copy(&mut stdout, &mut out)?;
+            stderr.join().expect("stderr thread panicked")?;
+            Ok::<_, std::io::Error>(())
+        })?;
+        spawned.wait()
+    }
+
+    /// Runs the command, prefixing each line of its stdout before writing it to `out`.
+    ///
+    /// `prefix` is a template expanded with [`expand`](Self::expand), so it may
+    /// contain `{}` and `{#}`. Stderr is inherited.
+    pub fn run_prefixed<W: std::io::Write>(
+        &self,
+        prefix: &str,
+        mut out: W,
+    ) -> std::io::Result<std::process::ExitStatus> {
+        use std::io::BufRead;
+
+        let prefix = self.expand(prefix);
+        let mut spawned = self.spawn_pipeline(self.command(), |command| {
+            command.stdout(std::process::Stdio::piped());
+        })?;
+        let stdout = spawned.last.stdout.take().expect("piped stdout");
+        let mut reader = std::io::BufReader::new(stdout);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            out.write_all(prefix.as_bytes())?;
+            out.write_all(&line)?;
+            line.clear();
+        }
+        out.flush()?;
+        spawned.wait()
+    }
+
     /// Returns a string representation of the command.
     ///
     /// # Returns
     ///
     /// Returns a `String` that represents the full command to be executed.
     pub fn command_string(&self) -> String {
-        let command = self.args().join(" ");
-        format!("{} {}", self.program, command)
+        self.command_string_with(" ")
     }
-}
 
-pub struct PhCommandVec {
-    program: String,
-    args: Vec<String>,
-    phargs: Vec<String>,
-    args_has_ph: bool,
-}
+    /// Returns a string representation of the command joined by `sep`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("echo", vec!["{}", "b"], vec!["a"]);
+    /// let command = pcv.iter().next().unwrap();
+    /// assert_eq!(command.command_string_with(" \\\n  "), "echo \\\n  a \\\n  b");
+    /// ```
+    pub fn command_string_with(&self, sep: &str) -> String {
+        self.argv().join(sep)
+    }
 
-impl PhCommandVec {
-    pub fn new<P: Into<String>, A: Into<String>, H: Into<String>>(
-        program: P,
-        args: Vec<A>,
-        phargs: Vec<H>,
-    ) -> Self {
-        let args = args.into_iter().map(Into::into).collect::<Vec<_>>();
-        let phargs = phargs.into_iter().map(Into::into).collect::<Vec<_>>();
-        let args = extend_row(args.iter(), &phargs);
-        Self {
-            args_has_ph: row_has_ph(args.iter()),
-            program: program.into(),
-            args,
-            phargs,
-        }
+    /// Returns the command as a string a POSIX shell parses back into the
+    /// same argv, quoting words as needed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("echo", vec!["{}"], vec!["a b"]);
+    /// let command = pcv.iter().next().unwrap();
+    /// assert_eq!(command.command_string_quoted(), "echo 'a b'");
+    /// ```
+    pub fn command_string_quoted(&self) -> String {
+        self.argv()
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
     }
 
-    pub fn iter(&self) -> PhCommandIterZero<impl Iterator<Item = &String>> {
-        PhCommandIterZero {
-            program: &self.program,
-            args: &self.args,
-            phargs: self.phargs.iter(),
-            args_has_ph: self.args_has_ph,
-            is_first: true,
-        }
+    /// Same as [`command_string`](Self::command_string), but with the text each
+    /// placeholder was substituted with wrapped in `open` and `close`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("echo", vec!["{}", "b"], vec!["a"]);
+    /// let command = pcv.iter().next().unwrap();
+    /// assert_eq!(command.command_string_marked("<", ">"), "echo <a> b");
+    /// ```
+    pub fn command_string_marked(&self, open: &str, close: &str) -> String {
+        let ctx = self.context();
+        let mark = |template: &str| -> String {
+            scan(template, |token| {
+                ctx.expand(token)
+                    .map(|value| format!("{}{}{}", open, value, close))
+            })
+            .0
+        };
+        let mut argv = self.config.wrapper.clone();
+        argv.push(mark(self.program));
+        argv.extend(self.args.iter().map(|arg| {
+            arg.0
+                .iter()
+                .map(|part| match part {
+                    Part::Template(template) => mark(template),
+                    Part::Literal(s) => s.clone(),
+                })
+                .collect::<String>()
+        }));
+        argv.join(" ")
     }
-}
 
-pub struct PhCommandIterZero<'p, 'a, P>
-where
-    P: Iterator,
-    P::Item: Into<String>,
-{
-    program: &'p str,
-    args: &'a [String],
-    phargs: P,
-    args_has_ph: bool,
-    is_first: bool,
+    /// Same as [`command_string_quoted`](Self::command_string_quoted), but
+    /// quoted for `cmd.exe` on Windows, see [`shell_command`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("echo", vec!["{}"], vec!["a & b"]);
+    /// let command = pcv.iter().next().unwrap();
+    /// assert_eq!(command.command_string_cmd(), r#"echo "a & b""#);
+    /// ```
+    pub fn command_string_cmd(&self) -> String {
+        self.argv()
+            .iter()
+            .map(|arg| cmd_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
-impl<'p, 'a, P> Iterator for PhCommandIterZero<'p, 'a, P>
-where
-    P: Iterator,
-    P::Item: Into<String>,
-{
-    type Item = PhCommand<'p, 'a>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.args_has_ph && !self.is_first {
-            None
-        } else {
-            self.is_first = false;
-            self.phargs.next().map(|ph| PhCommand {
-                program: self.program,
-                args: self.args,
-                ph: ph.into(),
-            })
+/// Quotes `arg` for `cmd.exe`.
+///
+/// An argument that is empty or contains whitespace or quotes is wrapped in
+/// double quotes, with inner quotes doubled. Otherwise the special characters
+/// of `cmd.exe`, like `&` and `^`, are escaped with a caret.
+fn cmd_quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains([' ', '\t', '"']) {
+        format!("\"{}\"", arg.replace('"', "\"\""))
+    } else {
+        let mut quoted = String::with_capacity(arg.len());
+        for c in arg.chars() {
+            if "&|<>^()%!".contains(c) {
+                quoted.push('^');
+            }
+            quoted.push(c);
         }
+        quoted
     }
 }
 
-/// Transforms an array format string into individual strings with placeholders substituted.
-///
-/// This function interprets a format string and applies it to each item in `args`. If the format string
-/// is enclosed in brackets, each item replaces a `{}` placeholder within the format.
-///
-/// # Arguments
-///
-/// * `fmt` - The format string, potentially enclosed in brackets.
-/// * `args` - An iterator of items that will replace the `{}` placeholder.
+/// Reads at most `limit` bytes of `reader` into `buf`, then drains the rest.
 ///
-/// # Returns
+/// Returns true if anything was drained.
+fn read_limited<R: std::io::Read>(
+    reader: &mut R,
+    limit: usize,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<bool> {
+    use std::io::Read;
+
+    reader.take(limit as u64).read_to_end(buf)?;
+    Ok(std::io::copy(reader, &mut std::io::sink())? > 0)
+}
+
+/// Quotes `arg` for a POSIX shell, leaving words of only safe characters as is.
 ///
-/// Returns a vector of strings with each `arg` formatted according to `fmt`.
+/// Anything else, including the empty string, is put in single quotes, with
+/// each embedded `'` written as `'\''`. This is the quoting of
+/// [`PhCommand::command_string_quoted`].
 ///
 /// # Examples
 ///
 /// ```
-/// let result = phargs::extend_array("[{}.txt]", vec!["file1", "file2"]);
-/// assert_eq!(result, vec!["file1.txt", "file2.txt"]);
+/// assert_eq!(phargs::shell_quote("file.txt"), "file.txt");
+/// assert_eq!(phargs::shell_quote("a b"), "'a b'");
+/// assert_eq!(phargs::shell_quote("it's"), r"'it'\''s'");
 /// ```
-pub fn extend_array<'a, S: AsRef<str>, T: AsRef<str> + 'a + ?Sized>(
-    fmt: S,
-    args: impl IntoIterator<Item = &'a T>,
-) -> Vec<String> {
-    let (first, last) = (
-        fmt.as_ref().chars().next(),
-        fmt.as_ref().chars().next_back(),
-    );
-    if Some('[') == first && Some(']') == last {
-        let fmt = &fmt.as_ref()[1..fmt.as_ref().len() - 1];
-        args.into_iter()
-            .map(|s| fmt.replace("{}", s.as_ref()))
-            .collect::<Vec<_>>()
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
     } else {
-        vec![fmt.as_ref().to_string()]
+        format!("'{}'", arg.replace('\'', r"'\''"))
     }
 }
 
-pub fn row_has_ph<'a, T: AsRef<str> + 'a>(row: impl IntoIterator<Item = &'a T>) -> bool {
-    row.into_iter().any(|s| s.as_ref().contains("{}"))
+/// The processes of a spawned [`PhCommand`]: the final one, whose output is
+/// read, and the command itself if it is piped into another one.
+struct Spawned {
+    last: std::process::Child,
+    first: Option<std::process::Child>,
 }
 
-/// Extends a row of format strings into a flat list of formatted strings.
-///
-/// This function takes a collection of format strings, some of which may include bracketed placeholders,
-/// and flattens the result after applying each placeholder substitution.
-///
-/// # Arguments
-///
-/// * `row` - An iterable collection of format strings.
-/// * `args` - An array of strings to substitute into format strings.
-///
-/// # Returns
-///
-/// Returns a flattened vector of strings after substituting placeholders.
-///
-/// # Examples
+impl Spawned {
+    /// Captures stdout and stderr of `command`.
+    fn capture(command: &mut std::process::Command) {
+        command
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+    }
+
+    /// Waits for every process and returns the status of the final one.
+    fn wait(mut self) -> std::io::Result<std::process::ExitStatus> {
+        let status = self.last.wait();
+        if let Some(mut first) = self.first {
+            first.wait()?;
+        }
+        status
+    }
+
+    /// Same as [`wait`](Self::wait), but also collects the output of the final process.
+    fn wait_with_output(self) -> std::io::Result<std::process::Output> {
+        let output = self.last.wait_with_output();
+        if let Some(mut first) = self.first {
+            first.wait()?;
+        }
+        output
+    }
+}
+
+/// A command with its placeholders substituted, owning its argv.
 ///
-/// ```
-/// let formats = vec!["plain text", "[{}.txt]"];
-/// let args = vec!["file1", "file2"];
-/// let extended = phargs::extend_row(formats, &args);
-/// assert_eq!(extended, vec!["plain text", "file1.txt", "file2.txt"]);
-/// ```
-pub fn extend_row<'r, 'a, R: AsRef<str> + 'r + ?Sized, A: AsRef<str> + 'a>(
-    row: impl IntoIterator<Item = &'r R>,
-    args: &'a [A],
-) -> Vec<String> {
-    row.into_iter()
-        .flat_map(|s| extend_array(s, args.iter()))
-        .collect()
+/// Unlike [`PhCommand`], it does not borrow the [`PhCommandVec`] it came
+/// from, see [`PhCommandVec::to_vec`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedPhCommand {
+    program: String,
+    args: Vec<String>,
+    ph: String,
+    config: std::sync::Arc<Config>,
+    /// The working directory, with the placeholders of its template substituted.
+    current_dir: Option<String>,
+}
+
+impl OwnedPhCommand {
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Returns the pharg the command was created for; empty for `[..]` chunks.
+    pub fn ph(&self) -> &str {
+        &self.ph
+    }
+
+    /// Creates a `Command` ready to execute.
+    pub fn command(&self) -> std::process::Command {
+        let mut command = std::process::Command::new(&self.program);
+        command.args(&self.args);
+        self.config.apply(&mut command);
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        command
+    }
+
+    /// Returns the program and arguments joined with spaces.
+    pub fn command_string(&self) -> String {
+        std::iter::once(self.program.as_str())
+            .chain(self.args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Clone)]
+pub struct PhCommandVec {
+    program: String,
+    template: Vec<String>,
+    args: Vec<Arg>,
+    phargs: Vec<String>,
+    args_has_ph: bool,
+    max_chars: usize,
+    /// See [`PhCommandVec::max_expansions`].
+    max_expansions: usize,
+    /// Whether the template expands to more than `max_expansions` arguments,
+    /// in which case nothing is built and no command is produced.
+    too_many_expansions: bool,
+    chunks: Vec<Vec<Arg>>,
+    /// The values of the named lists, one row per pharg, see [`PhCommandVec::named`].
+    rows: Vec<Vec<String>>,
+    /// The line number of each pharg, see [`PhCommandVec::from_lines`].
+    lines: Vec<usize>,
+    /// How many leading arguments of `template` came from [`PhCommandVec::prefix_args`].
+    prefix_len: usize,
+    /// The programs after `program` that commands cycle through, see [`PhCommandVec::programs`].
+    alternates: Vec<String>,
+    config: Config,
+}
+
+/// How the standard streams of a spawned command are connected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StdioMode {
+    /// Keep the defaults of `std::process::Command`.
+    #[default]
+    Default,
+    /// Explicitly inherit stdin, stdout and stderr of the current process.
+    Inherit,
+    /// Connect stdin, stdout and stderr to the null device.
+    Null,
+}
+
+impl StdioMode {
+    /// Applies this mode to `command`.
+    pub fn apply(self, command: &mut std::process::Command) -> &mut std::process::Command {
+        use std::process::Stdio;
+        match self {
+            StdioMode::Default => command,
+            StdioMode::Inherit => command
+                .stdin(Stdio::inherit())
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+            StdioMode::Null => command
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        }
+    }
+}
+
+/// Settings shared by every command of a [`PhCommandVec`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Config {
+    delimiter: Option<String>,
+    stdio: StdioMode,
+    pipe: Option<(String, Vec<String>)>,
+    count_start: i64,
+    count_step: i64,
+    names: Vec<String>,
+    /// The wrapper programs every command is run under, see [`PhCommandVec::wrap`].
+    wrapper: Vec<String>,
+    /// The `PATH` of the commands, see [`PhCommandVec::path`].
+    path: Option<String>,
+    /// The directory `{rel}` is relative to, see [`PhCommandVec::base`].
+    base: Option<String>,
+    /// The variables kept in a cleared environment, see [`PhCommandVec::clear_env`].
+    keep_env: Option<Vec<String>>,
+    /// Whether arguments are only substituted after their first `=`, see
+    /// [`PhCommandVec::value_only`].
+    value_only: bool,
+    /// The delimiter of sublists in fields, see [`PhCommandVec::sub_delimiter`].
+    sub_delimiter: Option<String>,
+    /// The commands run once before and after the batch, see [`PhCommandVec::pre`].
+    pre: Option<(String, Vec<String>)>,
+    post: Option<(String, Vec<String>)>,
+    /// Whether `{size}` and `{mtime}` of missing files fail [`PhCommandVec::validate`].
+    strict_metadata: bool,
+    /// The command fed with the output of all commands, see [`PhCommandVec::reduce_with`].
+    reducer: Option<(String, Vec<String>)>,
+    /// The `argv[0]` of the commands, see [`PhCommandVec::arg0`].
+    arg0: Option<String>,
+    /// Whether `[..]` arguments stay without phargs, see [`PhCommandVec::keep_empty_brackets`].
+    keep_empty_brackets: bool,
+    /// Whether `{a,b}` sets are expanded, see [`PhCommandVec::brace_sets`].
+    brace_sets: bool,
+    /// The working directory of commands, a template, see [`PhCommandVec::current_dir`].
+    current_dir: Option<String>,
+    /// The template written to the stdin of commands, see [`PhCommandVec::stdin_template`].
+    stdin_template: Option<String>,
+    /// The niceness increment of the commands, see [`PhCommandVec::nice`].
+    #[cfg(feature = "nice")]
+    nice: Option<i32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            delimiter: None,
+            stdio: StdioMode::default(),
+            pipe: None,
+            count_start: 1,
+            count_step: 1,
+            names: Vec::new(),
+            wrapper: Vec::new(),
+            path: None,
+            base: None,
+            keep_env: None,
+            value_only: false,
+            sub_delimiter: None,
+            pre: None,
+            post: None,
+            strict_metadata: false,
+            reducer: None,
+            arg0: None,
+            keep_empty_brackets: false,
+            brace_sets: false,
+            current_dir: None,
+            stdin_template: None,
+            #[cfg(feature = "nice")]
+            nice: None,
+        }
+    }
+}
+
+impl Config {
+    /// Creates a non-templated command run around the batch.
+    fn hook_command(&self, (program, args): &(String, Vec<String>)) -> std::process::Command {
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+        self.apply(&mut command);
+        command
+    }
+
+    /// Applies the stdio mode, environment, argv[0] and priority to `command`.
+    fn apply(&self, command: &mut std::process::Command) {
+        self.stdio.apply(command);
+        self.apply_env(command);
+        #[cfg(unix)]
+        if let Some(arg0) = self.arg0.as_ref().filter(|_| self.wrapper.is_empty()) {
+            std::os::unix::process::CommandExt::arg0(command, arg0);
+        }
+        #[cfg(all(unix, feature = "nice"))]
+        if let Some(increment) = self.nice {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: nice(2) is async-signal-safe and touches no memory of
+            // the parent. It can only fail when raising the priority without
+            // privileges, which then leaves it unchanged.
+            unsafe {
+                command.pre_exec(move || {
+                    libc::nice(increment);
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    /// Sets the environment of `command`.
+    fn apply_env(&self, command: &mut std::process::Command) {
+        if let Some(keep) = &self.keep_env {
+            command.env_clear();
+            for name in keep {
+                if let Some(value) = std::env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        if let Some(path) = &self.path {
+            command.env("PATH", path);
+        }
+    }
+
+    /// Creates the context of the command with the 1-based sequence number `seq`.
+    fn context<'a>(&'a self, ph: &'a str, seq: Option<usize>) -> Context<'a> {
+        Context {
+            ph,
+            seq: seq.map(|n| self.count_start + (n as i64 - 1) * self.count_step),
+            delimiter: self.delimiter.as_deref(),
+            names: &self.names,
+            row: &[],
+            line: None,
+            item: None,
+            base: self.base.as_deref(),
+            value_only: self.value_only,
+        }
+    }
+
+    /// Returns the formats `fmt` stands for, one per alternative of its brace
+    /// set if [`PhCommandVec::brace_sets`] is enabled.
+    fn alternatives(&self, fmt: &str) -> Vec<String> {
+        if self.brace_sets {
+            expand_brace_set(fmt)
+        } else {
+            vec![fmt.to_string()]
+        }
+    }
+
+    /// Returns the number of arguments `fmt` expands to with `phargs` phargs,
+    /// not counting sublists split by [`PhCommandVec::sub_delimiter`].
+    fn expansion_count(&self, fmt: &str, phargs: usize) -> usize {
+        match bracket_format(fmt) {
+            Some(_) if phargs == 0 && self.keep_empty_brackets => 1,
+            Some(fmt) => phargs.saturating_mul(self.alternatives(fmt).len()),
+            None => self.alternatives(fmt).len(),
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl PhCommandVec {
+    pub fn new<P: Into<String>, A: Into<String>, H: Into<String>>(
+        program: P,
+        args: Vec<A>,
+        phargs: Vec<H>,
+    ) -> Self {
+        let mut v = Self {
+            program: program.into(),
+            template: args.into_iter().map(Into::into).collect(),
+            args: Vec::new(),
+            phargs: phargs.into_iter().map(Into::into).collect(),
+            args_has_ph: false,
+            max_chars: default_max_chars(),
+            max_expansions: usize::MAX,
+            too_many_expansions: false,
+            chunks: Vec::new(),
+            rows: Vec::new(),
+            lines: Vec::new(),
+            prefix_len: 0,
+            alternates: Vec::new(),
+            config: Config::default(),
+        };
+        v.update();
+        v
+    }
+
+    /// Same as [`new`](Self::new), but the commands cycle through `programs`:
+    /// the first command runs the first program, the second one the second,
+    /// and so on, starting over after the last.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `programs` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::programs(vec!["tool-a", "tool-b"], vec!["{}"], vec!["1", "2", "3"]);
+    /// let programs = pcv.iter().map(|c| c.expanded_program()).collect::<Vec<_>>();
+    /// assert_eq!(programs, ["tool-a", "tool-b", "tool-a"]);
+    /// ```
+    pub fn programs<P: Into<String>, A: Into<String>, H: Into<String>>(
+        programs: Vec<P>,
+        args: Vec<A>,
+        phargs: Vec<H>,
+    ) -> Self {
+        let mut programs = programs.into_iter().map(Into::into);
+        let program = programs.next().expect("at least one program");
+        let mut v = Self::new(program, args, phargs);
+        v.alternates = programs.collect();
+        v.update();
+        v
+    }
+
+    /// Same as [`new`](Self::new), but fails with `InvalidInput` if `program`
+    /// is empty instead of failing to spawn it later.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// assert!(phargs::PhCommandVec::try_new("echo", vec!["{}"], vec!["a"]).is_ok());
+    /// assert!(phargs::PhCommandVec::try_new("", vec!["{}"], vec!["a"]).is_err());
+    /// ```
+    pub fn try_new<P: Into<String>, A: Into<String>, H: Into<String>>(
+        program: P,
+        args: Vec<A>,
+        phargs: Vec<H>,
+    ) -> std::io::Result<Self> {
+        let program = program.into();
+        if program.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "program is empty",
+            ));
+        }
+        Ok(Self::new(program, args, phargs))
+    }
+
+    /// Creates commands from named lists, zipped by index.
+    ///
+    /// Each `{name}` placeholder is substituted with the value of the list
+    /// `name` for the command, and `{}` with all of its values joined by a
+    /// space. There are as many commands as elements in the shortest list.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let lists = std::collections::HashMap::from([
+    ///     ("src".to_string(), vec!["a".to_string(), "b".to_string()]),
+    ///     ("dst".to_string(), vec!["x".to_string(), "y".to_string(), "z".to_string()]),
+    /// ]);
+    /// let pcv = phargs::PhCommandVec::named("cp", vec!["{src}", "{dst}"], lists);
+    /// let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+    /// assert_eq!(commands, ["cp a x", "cp b y"]);
+    /// ```
+    pub fn named<P: Into<String>, A: Into<String>>(
+        program: P,
+        args: Vec<A>,
+        lists: std::collections::HashMap<String, Vec<String>>,
+    ) -> Self {
+        let mut names = lists.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        let len = lists.values().map(Vec::len).min().unwrap_or(0);
+        let rows = (0..len)
+            .map(|i| {
+                names
+                    .iter()
+                    .map(|n| lists[n][i].clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let phargs = rows.iter().map(|row| row.join(" ")).collect();
+
+        let mut v = Self::new(program, args, phargs);
+        v.config.names = names;
+        v.rows = rows;
+        v.update();
+        v
+    }
+
+    /// Creates commands from phargs numbered by the line they were read from.
+    ///
+    /// The line number is available as the `{.line}` placeholder, e.g. for
+    /// error messages. See [`read_lines`].
+    pub fn from_lines<P: Into<String>, A: Into<String>>(
+        program: P,
+        args: Vec<A>,
+        lines: Vec<(usize, String)>,
+    ) -> Self {
+        let (numbers, phargs): (Vec<_>, Vec<_>) = lines.into_iter().unzip();
+        let mut v = Self::new(program, args, phargs);
+        v.lines = numbers;
+        v.update();
+        v
+    }
+
+    /// Reorders the phargs, together with their named values and line numbers,
+    /// by `compare`.
+    fn sort_phargs_by(&mut self, mut compare: impl FnMut(&str, &str) -> std::cmp::Ordering) {
+        fn permute<T: Clone>(v: &mut Vec<T>, order: &[usize]) {
+            if !v.is_empty() {
+                *v = order.iter().map(|&i| v[i].clone()).collect();
+            }
+        }
+        let mut order = (0..self.phargs.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| compare(&self.phargs[a], &self.phargs[b]));
+        permute(&mut self.phargs, &order);
+        permute(&mut self.rows, &order);
+        permute(&mut self.lines, &order);
+        self.update();
+    }
+
+    /// Keeps the phargs, together with their named values and line numbers,
+    /// for which `keep` is true.
+    fn retain_phargs(&mut self, keep: &[bool]) {
+        fn retain<T>(v: &mut Vec<T>, keep: &[bool]) {
+            let mut flags = keep.iter();
+            v.retain(|_| *flags.next().unwrap_or(&true));
+        }
+        retain(&mut self.phargs, keep);
+        retain(&mut self.rows, keep);
+        retain(&mut self.lines, keep);
+        self.update();
+    }
+
+    /// Re-expands the argument template after the phargs have changed.
+    fn update(&mut self) {
+        let template = self.template.iter().map(String::as_str);
+        self.too_many_expansions = check_expansions(
+            template,
+            self.phargs.len(),
+            &self.config,
+            self.max_expansions,
+        )
+        .is_err();
+        self.chunks.clear();
+        if self.too_many_expansions {
+            self.args.clear();
+            self.args_has_ph = false;
+            return;
+        }
+        self.args = extend_row_in(&self.template, &self.phargs, &self.config);
+        self.args_has_ph = std::iter::once(&self.program)
+            .chain(&self.alternates)
+            .any(|program| self.is_template(program))
+            || self.args.iter().any(|arg| self.is_template_arg(arg))
+            || (self.config.stdin_template.iter())
+                .chain(&self.config.current_dir)
+                .any(|s| self.is_template(s));
+        if !self.args_has_ph {
+            let mut chunks = self
+                .chunk_phargs()
+                .into_iter()
+                .map(|phargs| extend_row_in(&self.template, phargs, &self.config))
+                .collect::<Vec<_>>();
+            if chunks.len() > 1 {
+                self.args = chunks.remove(0);
+                self.chunks = chunks;
+            }
+        }
+    }
+
+    /// Splits the phargs so that the argv of each appending command stays
+    /// within `max_chars` bytes. A pharg that alone exceeds the limit still
+    /// gets its own chunk.
+    fn chunk_phargs(&self) -> Vec<&[String]> {
+        let size = |s: &str| s.len() + 1;
+        let mut fixed = size(&self.program);
+        let mut brackets = Vec::new();
+        for arg in &self.template {
+            match bracket_format(arg) {
+                Some(fmt) => brackets.push(fmt),
+                None => fixed += size(arg),
+            }
+        }
+
+        let mut chunks = Vec::new();
+        let (mut start, mut total) = (0, fixed);
+        for (i, ph) in self.phargs.iter().enumerate() {
+            let cost = brackets
+                .iter()
+                .map(|fmt| size(&self.config.context(ph, None).substitute(fmt)))
+                .sum::<usize>();
+            if i > start && total + cost > self.max_chars {
+                chunks.push(&self.phargs[start..i]);
+                (start, total) = (i, fixed);
+            }
+            total += cost;
+        }
+        chunks.push(&self.phargs[start..]);
+        chunks
+    }
+
+    /// Limits the total argv size, in bytes, of commands that append all
+    /// phargs through bracket templates.
+    ///
+    /// Phargs that would not fit are moved to additional invocations of the
+    /// same command. Defaults to [`default_max_chars`].
+    pub fn max_chars(mut self, bytes: usize) -> Self {
+        self.max_chars = bytes;
+        self.update();
+        self
+    }
+
+    /// Limits the number of arguments the template may expand to with all
+    /// phargs, which protects against untrusted templates.
+    ///
+    /// The count is checked before any argument is built. Over the limit no
+    /// command is produced, and [`check_expansions`](Self::check_expansions)
+    /// fails. Defaults to no limit, so that large inputs are still split by
+    /// [`max_chars`](Self::max_chars); [`DEFAULT_MAX_EXPANSIONS`] suits
+    /// untrusted templates.
+    pub fn max_expansions(mut self, limit: usize) -> Self {
+        self.max_expansions = limit;
+        self.update();
+        self
+    }
+
+    /// Checks that the template stays within
+    /// [`max_expansions`](Self::max_expansions).
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidInput` error if the template expands to too many
+    /// arguments.
+    pub fn check_expansions(&self) -> std::io::Result<()> {
+        let template = self.template.iter().map(String::as_str);
+        check_expansions(
+            template,
+            self.phargs.len(),
+            &self.config,
+            self.max_expansions,
+        )
+    }
+
+    /// Splits each pharg into fields separated by `delimiter`.
+    ///
+    /// Field `N` (1-based) is then available as the `{N}` placeholder, both in
+    /// plain arguments and in bracket templates. Missing fields are substituted
+    /// with an empty string.
+    ///
+    /// A range `{start:end}` then selects the fields `start` through `end`,
+    /// joined by `delimiter`, instead of characters. Either bound may be
+    /// omitted, and ranges are clamped to the fields the pharg has.
+    ///
+    /// `{#fields}` is replaced with the number of fields of the pharg.
+    pub fn field_delimiter<D: Into<String>>(mut self, delimiter: D) -> Self {
+        self.config.delimiter = Some(delimiter.into());
+        self.update();
+        self
+    }
+
+    /// Pipes the stdout of every command into `program` with `args`.
+    ///
+    /// The piped command is substituted with the same pharg as the main one.
+    /// It is used by [`PhCommand::status`] and [`PhCommand::output`].
+    pub fn pipe_to<P: Into<String>, A: Into<String>>(mut self, program: P, args: Vec<A>) -> Self {
+        self.config.pipe = Some((program.into(), args.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Runs `program` with `args` once before the batch in [`run_batch`](Self::run_batch).
+    ///
+    /// Unlike the commands of the batch, it is not a template.
+    pub fn pre<P: Into<String>, A: Into<String>>(mut self, program: P, args: Vec<A>) -> Self {
+        self.config.pre = Some((program.into(), args.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Runs `program` with `args` once after the batch in [`run_batch`](Self::run_batch).
+    pub fn post<P: Into<String>, A: Into<String>>(mut self, program: P, args: Vec<A>) -> Self {
+        self.config.post = Some((program.into(), args.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Creates the command set with [`pre`](Self::pre), if any.
+    pub fn pre_command(&self) -> Option<std::process::Command> {
+        self.config
+            .pre
+            .as_ref()
+            .map(|pre| self.config.hook_command(pre))
+    }
+
+    /// Creates the command set with [`post`](Self::post), if any.
+    pub fn post_command(&self) -> Option<std::process::Command> {
+        self.config
+            .post
+            .as_ref()
+            .map(|post| self.config.hook_command(post))
+    }
+
+    /// Runs every command under the `wrapper` programs, such as `nice` or `time`.
+    pub fn wrap<W: Into<String>>(mut self, wrapper: Vec<W>) -> Self {
+        self.config.wrapper = wrapper.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Expands a bracket template with a field `{N}` once per item of that
+    /// field, which is split by `delimiter`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("echo", vec!["[{1}]", "{2}"], vec!["a|b|c:x"])
+    ///     .field_delimiter(":")
+    ///     .sub_delimiter("|");
+    /// assert_eq!(pcv.iter().next().unwrap().command_string(), "echo a b c x");
+    /// ```
+    pub fn sub_delimiter<D: Into<String>>(mut self, delimiter: D) -> Self {
+        self.config.sub_delimiter = Some(delimiter.into());
+        self.update();
+        self
+    }
+
+    /// Runs the commands with their niceness increased by `increment`, which
+    /// lowers their priority. This has no effect on non-Unix platforms.
+    #[cfg(feature = "nice")]
+    pub fn nice(mut self, increment: i32) -> Self {
+        self.config.nice = Some(increment);
+        self
+    }
+
+    /// Keeps `[..]` arguments as their literal content, such as `{}.txt` for
+    /// `[{}.txt]`, when there are no phargs. By default they expand to nothing.
+    ///
+    /// Without phargs, a command without per-pharg placeholders then still
+    /// runs once, like xargs does on empty input.
+    pub fn keep_empty_brackets(mut self, enabled: bool) -> Self {
+        self.config.keep_empty_brackets = enabled;
+        self.update();
+        self
+    }
+
+    /// Expands a brace set `{a,b,...}` in an argument into one argument per
+    /// alternative, like shell brace expansion. Only the first set of each
+    /// argument is expanded, and a backslash before the set keeps it as is.
+    ///
+    /// It is off by default, so that arguments such as JSON or awk programs
+    /// are passed through unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("cp", vec!["{}.{png,jpg}", r"\{a,b}"], vec!["img"])
+    ///     .brace_sets(true);
+    /// assert_eq!(pcv.first().unwrap(), "cp img.png img.jpg {a,b}");
+    /// ```
+    pub fn brace_sets(mut self, enabled: bool) -> Self {
+        self.config.brace_sets = enabled;
+        self.update();
+        self
+    }
+
+    /// Passes `name` as `argv[0]` of the program instead of its path, for
+    /// programs that behave according to their name, like busybox.
+    ///
+    /// This is only supported on Unix, and has no effect elsewhere. It also
+    /// has no effect under [`wrap`](Self::wrap), since the wrapper, not the
+    /// program, is what gets spawned.
+    pub fn arg0<N: Into<String>>(mut self, name: N) -> Self {
+        self.config.arg0 = Some(name.into());
+        self
+    }
+
+    /// Starts the commands with an empty environment, except for the variables
+    /// named in `keep`, which are copied from the current one if set.
+    ///
+    /// `PATH` set with [`path`](Self::path) is applied on top.
+    pub fn clear_env<K: Into<String>>(mut self, keep: Vec<K>) -> Self {
+        self.config.keep_env = Some(keep.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Runs each command in the directory `template`, substituted like its
+    /// arguments. A placeholder in `template` makes the batch run once per
+    /// pharg, like one in the arguments.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[cfg(unix)] {
+    /// let pcv = phargs::PhCommandVec::new("pwd", Vec::<String>::new(), vec!["/"])
+    ///     .current_dir("{}");
+    /// let output = pcv.iter().next().unwrap().output().unwrap();
+    /// assert_eq!(output.stdout, b"/\n");
+    /// # }
+    /// ```
+    pub fn current_dir<D: Into<String>>(mut self, template: D) -> Self {
+        self.config.current_dir = Some(template.into());
+        self.update();
+        self
+    }
+
+    /// Writes `template`, substituted for each command, to its stdin, which is
+    /// then closed. This applies to [`PhCommand::status`] and
+    /// [`PhCommand::output`]. A placeholder in `template` makes the batch run
+    /// once per pharg, like one in the arguments.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("cat", Vec::<String>::new(), vec!["a"])
+    ///     .stdin_template("payload-{}");
+    /// let output = pcv.iter().next().unwrap().output().unwrap();
+    /// assert_eq!(output.stdout, b"payload-a");
+    /// ```
+    pub fn stdin_template<T: Into<String>>(mut self, template: T) -> Self {
+        self.config.stdin_template = Some(template.into());
+        self.update();
+        self
+    }
+
+    /// Substitutes placeholders in arguments only after their first `=`, so
+    /// that in `key={}` the key is never substituted. Arguments without `=`
+    /// are kept as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("run", vec!["--in={}", "{}=x"], vec!["a"])
+    ///     .value_only(true);
+    /// assert_eq!(pcv.first().unwrap(), "run --in=a {}=x");
+    /// ```
+    pub fn value_only(mut self, enabled: bool) -> Self {
+        self.config.value_only = enabled;
+        self.update();
+        self
+    }
+
+    /// Inserts `args` after the program, before the arguments given so far.
+    /// They are templates like any other argument, and arguments of later
+    /// calls follow those of earlier ones.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("job", vec!["{}"], vec!["a", "b"])
+    ///     .prefix_args(vec!["--id", "{#}"]);
+    /// let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+    /// assert_eq!(commands, ["job --id 1 a", "job --id 2 b"]);
+    /// ```
+    pub fn prefix_args<A: Into<String>>(mut self, args: Vec<A>) -> Self {
+        let args = args.into_iter().map(Into::into).collect::<Vec<_>>();
+        let end = self.prefix_len + args.len();
+        self.template.splice(self.prefix_len..self.prefix_len, args);
+        self.prefix_len = end;
+        self.update();
+        self
+    }
+
+    /// Makes `{rel}` relative to `dir` instead of the current directory.
+    pub fn base<D: Into<String>>(mut self, dir: D) -> Self {
+        self.config.base = Some(dir.into());
+        self.update();
+        self
+    }
+
+    /// Sets `PATH` of the commands to `value`, which is also used to find their programs.
+    pub fn path<V: Into<String>>(mut self, value: V) -> Self {
+        self.config.path = Some(value.into());
+        self
+    }
+
+    /// Sets the counter substituted for `{#}` to `start`, incremented by `step`
+    /// for every following command.
+    pub fn counter(mut self, start: i64, step: i64) -> Self {
+        self.config.count_start = start;
+        self.config.count_step = step;
+        self
+    }
+
+    /// Sets how the standard streams of every command are connected.
+    pub fn stdio(mut self, mode: StdioMode) -> Self {
+        self.config.stdio = mode;
+        self
+    }
+
+    /// Returns true if `arg` contains a placeholder recognized with the current settings.
+    fn is_template(&self, arg: &str) -> bool {
+        let dummy = vec![String::new(); self.config.names.len()];
+        Context {
+            row: &dummy,
+            line: self.lines.first().copied(),
+            value_only: false,
+            ..self.config.context("", Some(1))
+        }
+        .has_placeholder(arg)
+    }
+
+    /// Returns true if a template piece of `arg` contains a placeholder.
+    fn is_template_arg(&self, arg: &Arg) -> bool {
+        arg.templates().any(|template| self.is_template(template))
+    }
+
+    /// Returns the program as given, before substitution.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Returns the arguments as given, before substitution.
+    pub fn template(&self) -> &[String] {
+        &self.template
+    }
+
+    /// Returns the phargs the commands are built from.
+    pub fn phargs(&self) -> &[String] {
+        &self.phargs
+    }
+
+    /// Returns, for each argument as originally given, whether it is a template.
+    ///
+    /// An argument is a template if it is a bracket form, or contains any
+    /// placeholder recognized with the current settings.
+    pub fn template_args(&self) -> Vec<bool> {
+        self.template
+            .iter()
+            .map(|arg| {
+                bracket_format(arg).is_some()
+                    || self.is_template_arg(&Arg::template(arg, self.config.value_only))
+            })
+            .collect()
+    }
+
+    /// Collapses all phargs into a single one, joined by `sep`.
+    ///
+    /// The command then runs once, with `{}` substituted by the joined phargs.
+    pub fn join(mut self, sep: &str) -> Self {
+        self.phargs = vec![self.phargs.join(sep)];
+        self.rows.clear();
+        self.lines.clear();
+        self.update();
+        self
+    }
+
+    /// Drops the first pharg, or the first row of named lists, such as the
+    /// header of a CSV file. It does nothing if there are no phargs.
+    pub fn skip_header(mut self, enabled: bool) -> Self {
+        if enabled && !self.phargs.is_empty() {
+            let mut keep = vec![true; self.phargs.len()];
+            keep[0] = false;
+            self.retain_phargs(&keep);
+        }
+        self
+    }
+
+    /// Sorts phargs lexicographically before building commands.
+    ///
+    /// Does nothing unless `enabled` is true.
+    pub fn sort(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.sort_phargs_by(str::cmp);
+        }
+        self
+    }
+
+    /// Sorts phargs with integer-aware ordering before building commands.
+    ///
+    /// Phargs that parse as integers are ordered by value and placed before
+    /// all others, which keep lexicographic order. Does nothing unless
+    /// `enabled` is true.
+    pub fn numeric_sort(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.sort_phargs_by(numeric_cmp);
+        }
+        self
+    }
+
+    /// Keeps only the phargs for which `keep` returns true, such as those not
+    /// yet recorded in a [`Checkpoint`].
+    pub fn retain<F: FnMut(&str) -> bool>(mut self, mut keep: F) -> Self {
+        let keep = self.phargs.iter().map(|ph| keep(ph)).collect::<Vec<_>>();
+        self.retain_phargs(&keep);
+        self
+    }
+
+    /// Drops phargs whose expanded command is identical to an earlier one.
+    ///
+    /// Commands are compared by their [`PhCommand::command_string`], and the
+    /// first occurrence is kept. Does nothing unless `enabled` is true.
+    pub fn dedup_commands(mut self, enabled: bool) -> Self {
+        if enabled && self.args_has_ph {
+            let mut seen = std::collections::HashSet::new();
+            let keep = self
+                .iter()
+                .map(|c| seen.insert(c.command_string()))
+                .collect::<Vec<_>>();
+            self.retain_phargs(&keep);
+        }
+        self
+    }
+
+    /// Runs the batch between its [`pre`](Self::pre) and [`post`](Self::post) commands.
+    ///
+    /// Commands run in order until one fails. The post-command runs even if a
+    /// command failed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, without running the batch, if the pre-command fails.
+    ///
+    /// # Returns
+    ///
+    /// Returns the status of every command that was run.
+    pub fn run_batch(&self) -> std::io::Result<Vec<std::process::ExitStatus>> {
+        if let Some(mut pre) = self.pre_command() {
+            let status = pre.status()?;
+            if !status.success() {
+                return Err(std::io::Error::other(format!(
+                    "pre-command failed: {}",
+                    status
+                )));
+            }
+        }
+        let mut statuses = Vec::new();
+        let mut result = Ok(());
+        for c in self.iter() {
+            match c.status() {
+                Ok(status) => {
+                    statuses.push(status);
+                    if !status.success() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if let Some(mut post) = self.post_command() {
+            post.status()?;
+        }
+        result.map(|()| statuses)
+    }
+
+    /// Same as [`run_batch`](Self::run_batch), but returns how the batch went.
+    /// The commands after a failed one count as skipped.
+    pub fn run_batch_summary(&self) -> std::io::Result<RunSummary> {
+        let results = self.run_batch()?.into_iter().map(Ok).collect::<Vec<_>>();
+        Ok(RunSummary::new(self.len(), &results))
+    }
+
+    /// Returns an iterator that runs each command when it is advanced, one at
+    /// a time, and yields it with its status.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("{}", Vec::<String>::new(), vec!["true", "false"]);
+    /// let failed = pcv.run_iter().find(|(_, status)| !status.as_ref().unwrap().success());
+    /// assert_eq!(failed.unwrap().0.ph(), "false");
+    /// ```
+    pub fn run_iter(
+        &self,
+    ) -> impl Iterator<Item = (PhCommand<'_, '_>, std::io::Result<std::process::ExitStatus>)> {
+        self.iter().map(|c| {
+            let status = c.status();
+            (c, status)
+        })
+    }
+
+    /// Sets the reducer of [`run_reduce`](Self::run_reduce), which is not a template.
+    pub fn reduce_with<P: Into<String>, A: Into<String>>(
+        mut self,
+        program: P,
+        args: Vec<A>,
+    ) -> Self {
+        self.config.reducer = Some((program.into(), args.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Runs every command, then pipes their concatenated stdout into the
+    /// reducer set with [`reduce_with`](Self::reduce_with).
+    ///
+    /// The stdout of the commands is always captured, and commands set with
+    /// [`pipe_to`](Self::pipe_to) are not run.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no reducer is set, or if a command fails; the
+    /// reducer is not run then.
+    ///
+    /// # Returns
+    ///
+    /// Returns the output of the reducer.
+    pub fn run_reduce(&self) -> std::io::Result<std::process::Output> {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let reducer = self.config.reducer.as_ref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "no reducer set")
+        })?;
+        let mut input = Vec::new();
+        for c in self.iter() {
+            let output = c
+                .spawn_pipeline(c.command(), Spawned::capture)?
+                .wait_with_output()?;
+            if !output.status.success() {
+                return Err(std::io::Error::other(format!(
+                    "{} failed: {}",
+                    c.command_string(),
+                    output.status
+                )));
+            }
+            input.extend(output.stdout);
+        }
+
+        let mut child = self
+            .config
+            .hook_command(reducer)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        std::thread::scope(|s| {
+            // The reducer may exit without reading all of its input.
+            s.spawn(move || stdin.write_all(&input));
+            child.wait_with_output()
+        })
+    }
+
+    /// Groups the commands by a key derived from their pharg, such as its
+    /// extension. Commands keep their order within a group.
+    ///
+    /// Commands appending `[..]` arguments have no pharg of their own and are
+    /// grouped by `key("")`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("echo", vec!["{}"], vec!["a.txt", "b.md"]);
+    /// let groups = pcv.group_by(|ph| ph.rsplit('.').next().unwrap().to_string());
+    /// assert_eq!(groups["md"][0].command_string(), "echo b.md");
+    /// ```
+    pub fn group_by<F: Fn(&str) -> String>(
+        &self,
+        key: F,
+    ) -> std::collections::HashMap<String, Vec<PhCommand<'_, '_>>> {
+        let mut groups = std::collections::HashMap::<_, Vec<_>>::new();
+        for command in self.iter() {
+            groups.entry(key(command.ph())).or_default().push(command);
+        }
+        groups
+    }
+
+    /// Runs the commands in parallel, with at most one running per permit of
+    /// `permits`. Sharing the semaphore between batches, even ones run from
+    /// different threads, bounds their total concurrency.
+    ///
+    /// Like [`run_argvs`], no new command is started once one has failed.
+    ///
+    /// # Returns
+    ///
+    /// Returns the status of every command that was started, in order.
+    pub fn run_parallel_with_permits(
+        &self,
+        permits: &Semaphore,
+    ) -> Vec<std::io::Result<std::process::ExitStatus>> {
+        let options = RunOptions::default().jobs(permits.capacity().min(self.len()));
+        run::run_each(self.iter(), &options, Some(permits), |c| c.status())
+    }
+
+    /// Runs every command in order and measures how long each one took.
+    ///
+    /// All commands are run, regardless of whether earlier ones failed.
+    ///
+    /// # Returns
+    ///
+    /// Returns one `(command_string, duration, status)` entry per command.
+    pub fn run_timed(
+        &self,
+    ) -> Vec<(
+        String,
+        std::time::Duration,
+        std::io::Result<std::process::ExitStatus>,
+    )> {
+        self.iter()
+            .map(|c| {
+                let start = std::time::Instant::now();
+                let status = c.status();
+                (c.command_string(), start.elapsed(), status)
+            })
+            .collect()
+    }
+
+    /// Runs commands in order until one succeeds.
+    ///
+    /// Commands that fail, or can't be started, are skipped.
+    ///
+    /// # Returns
+    ///
+    /// Returns the command string and status of the first successful command,
+    /// or `None` if all of them failed.
+    pub fn run_until_success(&self) -> Option<(String, std::process::ExitStatus)> {
+        self.run_until_success_by(|_, result| result.as_ref().is_ok_and(|s| s.success()))
+    }
+
+    /// Same as [`run_until_success`](Self::run_until_success), but
+    /// `is_success` decides whether a command succeeded from its result.
+    ///
+    /// It sees the error of a command that couldn't be started too, so that
+    /// it can report it. Such a command never counts as successful.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pcv = phargs::PhCommandVec::new("sh", vec!["-c", "exit {}"], vec!["3", "0"]);
+    /// let (command, _) = pcv
+    ///     .run_until_success_by(|_, result| result.as_ref().is_ok_and(|s| s.code() == Some(3)))
+    ///     .unwrap();
+    /// assert_eq!(command, "sh -c exit 3");
+    /// ```
+    pub fn run_until_success_by(
+        &self,
+        mut is_success: impl FnMut(
+            &PhCommand<'_, '_>,
+            &std::io::Result<std::process::ExitStatus>,
+        ) -> bool,
+    ) -> Option<(String, std::process::ExitStatus)> {
+        self.iter().find_map(|c| {
+            let result = c.status();
+            match (is_success(&c, &result), result) {
+                (true, Ok(status)) => Some((c.command_string(), status)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns the programs that can't be found by [`which`].
+    ///
+    /// Each program is substituted per command, and reported once in the order
+    /// it first appears.
+    pub fn missing_programs(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+        for c in self.iter() {
+            let program = c.expanded_program();
+            let found = match &self.config.path {
+                Some(path) => which_in(&program, path.as_ref()),
+                None => which(&program),
+            };
+            if !missing.contains(&program) && found.is_none() {
+                missing.push(program);
+            }
+        }
+        missing
+    }
+
+    /// Splits the phargs into `k` contiguous groups of nearly equal size.
+    ///
+    /// Each group keeps the program, arguments and settings of `self`. Groups
+    /// are empty if there are fewer than `k` phargs.
+    pub fn split_chunks(&self, k: usize) -> Vec<PhCommandVec> {
+        let n = self.phargs.len();
+        (0..k)
+            .map(|i| {
+                let group = i * n / k..(i + 1) * n / k;
+                let keep = (0..n).map(|j| group.contains(&j)).collect::<Vec<_>>();
+                let mut chunk = self.clone();
+                chunk.retain_phargs(&keep);
+                chunk
+            })
+            .collect()
+    }
+
+    /// Collects the commands of [`iter`](Self::iter) into owned values.
+    ///
+    /// They share the settings of the vector, so that
+    /// [`OwnedPhCommand::command`] is set up like [`PhCommand::command`].
+    pub fn to_vec(&self) -> Vec<OwnedPhCommand> {
+        let config = std::sync::Arc::new(self.config.clone());
+        self.iter()
+            .map(|c| {
+                let mut args = c.argv();
+                OwnedPhCommand {
+                    program: args.remove(0),
+                    args,
+                    ph: c.ph.clone(),
+                    config: config.clone(),
+                    current_dir: self.config.current_dir.as_ref().map(|dir| c.expand(dir)),
+                }
+            })
+            .collect()
+    }
+
+    /// Checks that every placeholder of the templates resolves for every pharg.
+    ///
+    /// # Errors
+    ///
+    /// Returns the unresolved placeholders, such as `{3}` for phargs with
+    /// fewer than three fields, or `{size}` for a missing file with
+    /// [`strict_metadata`](Self::strict_metadata).
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut unresolved = Vec::new();
+        for ph in &self.phargs {
+            let ctx = self.config.context(ph, None);
+            let programs = std::iter::once(&self.program).chain(&self.alternates);
+            for template in programs.chain(&self.template) {
+                let template = bracket_format(template).unwrap_or(template);
+                for token in ctx.unresolved(template, self.config.strict_metadata) {
+                    if !unresolved.contains(&token) {
+                        unresolved.push(token);
+                    }
+                }
+            }
+        }
+        if unresolved.is_empty() {
+            Ok(())
+        } else {
+            Err(unresolved)
+        }
+    }
+
+    /// Makes [`validate`](Self::validate) report `{size}` and `{mtime}` of
+    /// phargs that aren't existing paths, instead of accepting them as `0`.
+    pub fn strict_metadata(mut self, enabled: bool) -> Self {
+        self.config.strict_metadata = enabled;
+        self
+    }
+
+    /// Returns true if the program or an argument has a per-pharg placeholder,
+    /// in which case [`iter`](Self::iter) yields one command per pharg.
+    /// Otherwise the command runs once, or once per `[..]` chunk.
+    pub fn args_has_placeholder(&self) -> bool {
+        self.args_has_ph
+    }
+
+    /// Returns the [`command_string_quoted`](PhCommand::command_string_quoted)
+    /// of every command, such as for writing them to a script.
+    pub fn command_strings_quoted(&self) -> Vec<String> {
+        self.iter().map(|c| c.command_string_quoted()).collect()
+    }
+
+    /// Returns the [`command_string`](PhCommand::command_string) of the first
+    /// command, without running anything.
+    pub fn first(&self) -> Option<String> {
+        self.iter().next().map(|c| c.command_string())
+    }
+
+    /// Returns the number of commands [`iter`](Self::iter) produces.
+    pub fn len(&self) -> usize {
+        if self.too_many_expansions {
+            return 0;
+        }
+        match self.phargs.len() {
+            0 if self.config.keep_empty_brackets && !self.args_has_ph => 1,
+            0 => 0,
+            n if self.args_has_ph => n,
+            _ => 1 + self.chunks.len(),
+        }
+    }
+
+    /// Returns true if no command would be run.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Same as [`iter`](Self::iter), but also yields the
+    /// [`command_string`](PhCommand::command_string) of each command, expanded
+    /// once, for loops that both log and run the commands.
+    pub fn iter_with_strings(&self) -> impl Iterator<Item = (PhCommand<'_, '_>, String)> {
+        self.iter().map(|c| {
+            let command_string = c.command_string();
+            (c, command_string)
+        })
+    }
+
+    pub fn iter(&self) -> PhCommandIterZero<'_, '_, impl Iterator<Item = &String>> {
+        PhCommandIterZero {
+            program: &self.program,
+            alternates: &self.alternates,
+            args: &self.args,
+            chunks: self.chunks.iter(),
+            rows: self.rows.iter(),
+            lines: self.lines.iter(),
+            phargs: self.phargs.iter(),
+            args_has_ph: self.args_has_ph,
+            is_first: !self.too_many_expansions,
+            seq: 0,
+            config: &self.config,
+        }
+    }
+}
+
+/// Returns the default argv size limit used by [`PhCommandVec::max_chars`].
+///
+/// On Unix this is `sysconf(ARG_MAX)` minus the size of the current
+/// environment and some headroom. Elsewhere a conservative constant is used.
+pub fn default_max_chars() -> usize {
+    #[cfg(unix)]
+    {
+        // SAFETY: sysconf has no preconditions.
+        let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+        if arg_max > 0 {
+            let env = std::env::vars_os()
+                .map(|(k, v)| k.len() + v.len() + 2)
+                .sum::<usize>();
+            return (arg_max as usize)
+                .saturating_sub(env)
+                .saturating_sub(2048)
+                .max(4096);
+        }
+    }
+    8191
+}
+
+fn numeric_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    match (a.trim().parse::<i64>(), b.trim().parse::<i64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+pub struct PhCommandIterZero<'p, 'a, P>
+where
+    P: Iterator,
+    P::Item: Into<String>,
+{
+    program: &'p str,
+    alternates: &'p [String],
+    args: &'a [Arg],
+    chunks: std::slice::Iter<'a, Vec<Arg>>,
+    rows: std::slice::Iter<'a, Vec<String>>,
+    lines: std::slice::Iter<'a, usize>,
+    phargs: P,
+    args_has_ph: bool,
+    is_first: bool,
+    seq: usize,
+    config: &'p Config,
+}
+
+impl<'p, 'a, P> Iterator for PhCommandIterZero<'p, 'a, P>
+where
+    P: Iterator,
+    P::Item: Into<String>,
+{
+    type Item = PhCommand<'p, 'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let program = match self.seq % (self.alternates.len() + 1) {
+            0 => self.program,
+            i => &self.alternates[i - 1],
+        };
+        let command = if !self.args_has_ph && !self.is_first {
+            self.chunks.next().map(|args| PhCommand {
+                program,
+                args,
+                ph: String::new(),
+                seq: self.seq + 1,
+                row: &[],
+                line: None,
+                config: self.config,
+            })
+        } else {
+            self.is_first = false;
+            let ph = self.phargs.next().map(Into::into).or_else(|| {
+                let keep = self.config.keep_empty_brackets && !self.args_has_ph;
+                (keep && self.seq == 0).then(String::new)
+            });
+            ph.map(|ph| PhCommand {
+                program,
+                args: self.args,
+                ph,
+                seq: self.seq + 1,
+                row: self.rows.next().map_or(&[], Vec::as_slice),
+                line: self.lines.next().copied(),
+                config: self.config,
+            })
+        };
+        self.seq += command.is_some() as usize;
+        command
+    }
+}
+
+/// Transforms an array format string into individual strings with placeholders substituted.
+///
+/// This function interprets a format string and applies it to each item in `args`. If the format string
+/// is enclosed in brackets, each item replaces a `{}` placeholder within the format.
+///
+/// A whole argument `{*}` is a shorthand for `[{}]`, which expands to every
+/// item as a separate argument like `$@` of a shell. It is only recognized as
+/// the whole argument, so `x{*}` stays literal.
+///
+/// # Arguments
+///
+/// * `fmt` - The format string, potentially enclosed in brackets.
+/// * `args` - An iterator of items that will replace the `{}` placeholder.
+///
+/// # Returns
+///
+/// Returns a vector of strings with each `arg` formatted according to `fmt`.
+///
+/// # Examples
+///
+/// ```
+/// let result = phargs::extend_array("[{}.txt]", vec!["file1", "file2"]);
+/// assert_eq!(result, vec!["file1.txt", "file2.txt"]);
+/// ```
+pub fn extend_array<'a, S: AsRef<str>, T: AsRef<str> + 'a + ?Sized>(
+    fmt: S,
+    args: impl IntoIterator<Item = &'a T>,
+) -> Vec<String> {
+    extend_array_in(fmt.as_ref(), args, &Config::default())
+}
+
+/// Same as [`extend_array`], but each item is also split into fields by
+/// `delimiter`, which are available as `{1}`, `{2}`, ... in the format.
+///
+/// # Examples
+///
+/// ```
+/// let result = phargs::extend_array_fields("[{1}.{2}]", vec!["a:txt", "b:md"], ":");
+/// assert_eq!(result, vec!["a.txt", "b.md"]);
+/// ```
+pub fn extend_array_fields<'a, S: AsRef<str>, T: AsRef<str> + 'a + ?Sized>(
+    fmt: S,
+    args: impl IntoIterator<Item = &'a T>,
+    delimiter: &str,
+) -> Vec<String> {
+    let config = Config {
+        delimiter: Some(delimiter.to_string()),
+        ..Default::default()
+    };
+    extend_array_in(fmt.as_ref(), args, &config)
+}
+
+fn extend_array_in<'a, T: AsRef<str> + 'a + ?Sized>(
+    fmt: &str,
+    args: impl IntoIterator<Item = &'a T>,
+    config: &Config,
+) -> Vec<String> {
+    let mut out = Vec::new();
+    extend_array_into(fmt, args, config, &mut out);
+    out.into_iter().map(Arg::join).collect()
+}
+
+fn extend_array_into<'a, T: AsRef<str> + 'a + ?Sized>(
+    fmt: &str,
+    args: impl IntoIterator<Item = &'a T>,
+    config: &Config,
+    out: &mut Vec<Arg>,
+) {
+    match bracket_format(fmt) {
+        Some(fmt) => {
+            let fmts = config.alternatives(fmt);
+            let mut args = args.into_iter().peekable();
+            if args.peek().is_none() && config.keep_empty_brackets {
+                out.push(Arg(vec![Part::Literal(fmt.to_string())]));
+            }
+            for s in args {
+                let ctx = config.context(s.as_ref(), None);
+                for fmt in &fmts {
+                    match &config.sub_delimiter {
+                        Some(sub) => out.extend(ctx.sublist(fmt, sub)),
+                        None => out.push(ctx.substitute_arg(fmt)),
+                    }
+                }
+            }
+        }
+        None => out.extend(
+            config
+                .alternatives(fmt)
+                .iter()
+                .map(|fmt| Arg::template(fmt, config.value_only)),
+        ),
+    }
+}
+
+/// A piece of an [`Arg`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    /// Text whose placeholders are substituted for each command.
+    Template(String),
+    /// Text that is used as is, such as a value substituted into a `[..]`
+    /// argument, so that placeholders in phargs are never substituted again.
+    Literal(String),
+}
+
+/// An argument after `[..]` arguments are expanded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Arg(Vec<Part>);
+
+impl Arg {
+    /// Returns the argument `template` before substitution. With
+    /// `value_only`, only the text after its first `=` is a template.
+    fn template(template: &str, value_only: bool) -> Self {
+        if !value_only {
+            return Arg(vec![Part::Template(template.to_string())]);
+        }
+        match template.split_once('=') {
+            Some((key, value)) => Arg(vec![
+                Part::Literal(format!("{}=", key)),
+                Part::Template(value.to_string()),
+            ]),
+            None => Arg(vec![Part::Literal(template.to_string())]),
+        }
+    }
+
+    /// Returns the text of the pieces whose placeholders are still substituted.
+    fn templates(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|part| match part {
+            Part::Template(s) => Some(s.as_str()),
+            Part::Literal(_) => None,
+        })
+    }
+
+    /// Concatenates the pieces without substituting anything.
+    fn join(self) -> String {
+        self.0
+            .into_iter()
+            .map(|part| match part {
+                Part::Template(s) | Part::Literal(s) => s,
+            })
+            .collect()
+    }
+}
+
+/// Expands the first `{a,b,...}` set in `fmt` into one string per alternative.
+///
+/// Braces without a comma, such as placeholders, are kept as they are. As
+/// with placeholders, backslashes before a set are halved, and an odd number of
+/// them keeps the set literally.
+fn expand_brace_set(fmt: &str) -> Vec<String> {
+    let mut prefix = String::new();
+    let mut rest = fmt;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|i| i + start) else {
+            break;
+        };
+        let set = &rest[start + 1..end];
+        if !set.contains(',') {
+            prefix.push_str(&rest[..=start]);
+            rest = &rest[start + 1..];
+            continue;
+        }
+        let text = &rest[..start];
+        let backslashes = text.len() - text.trim_end_matches('\\').len();
+        prefix.push_str(&text[..text.len() - backslashes]);
+        prefix.push_str(&text[text.len() - backslashes / 2..]);
+        let suffix = &rest[end + 1..];
+        if backslashes % 2 == 1 {
+            prefix.push_str(&rest[start..=end]);
+            rest = suffix;
+            continue;
+        }
+        return set
+            .split(',')
+            .map(|alt| format!("{}{}{}", prefix, alt, suffix))
+            .collect();
+    }
+    prefix.push_str(rest);
+    vec![prefix]
+}
+
+/// Returns the inner format of a bracket template.
+///
+/// # Returns
+///
+/// Returns the string between the brackets for `[...]` forms, or `None` if `fmt`
+/// is not enclosed in brackets.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(phargs::parse_bracket("[{}.txt]"), Some("{}.txt"));
+/// assert_eq!(phargs::parse_bracket("{}.txt"), None);
+/// ```
+pub fn parse_bracket(fmt: &str) -> Option<&str> {
+    fmt.strip_prefix('[')?.strip_suffix(']')
+}
+
+/// Returns the format a whole argument expands once per pharg with, taking
+/// `{*}` as `[{}]`.
+fn bracket_format(fmt: &str) -> Option<&str> {
+    match fmt {
+        "{*}" => Some("{}"),
+        _ => parse_bracket(fmt),
+    }
+}
+
+pub fn row_has_ph<'a, T: AsRef<str> + 'a>(row: impl IntoIterator<Item = &'a T>) -> bool {
+    row.into_iter().any(|s| has_placeholder(s.as_ref()))
+}
+
+/// Substitutes every placeholder in `template` with (a part of) `ph`.
+///
+/// The following placeholders are recognized:
+///
+/// * `{}` - the whole `ph`.
+/// * `{start:end}` - the characters `start..end` of `ph`. Either bound may be
+///   omitted, and out-of-range bounds are clamped to the string.
+/// * `{abs}` - `ph` as a canonical absolute path, or `ph` itself if it can't
+///   be resolved, for example because it doesn't exist.
+/// * `{size}`, `{mtime}` - the size in bytes and the modification time as a
+///   UNIX timestamp of the file `ph`, or `0` if it doesn't exist.
+/// * `{rel}` - `ph` relative to the current directory, or to the directory
+///   set with [`PhCommandVec::base`]. A path outside of it is kept as is.
+/// * `{hash}` - 8 hex digits of the 32-bit FNV-1a hash of `ph`, stable across
+///   runs and platforms.
+/// * `{safe}` - `ph` with characters that are unsafe in file names, such as
+///   `/`, `:` and control characters, replaced by `_`.
+/// * `{url}` - `ph` percent-encoded, keeping only the unreserved characters
+///   `A-Z a-z 0-9 - . _ ~` as is.
+///
+/// A backslash before a placeholder escapes it, so `\{}` is a literal `{}`
+/// and `\\{}` is a backslash followed by `ph`.
+///
+/// Any other text, including unrecognized braces, is copied as is. Values
+/// are inserted in a single pass, so placeholders in `ph` itself are never
+/// substituted.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(phargs::substitute("{}.txt", "file1"), "file1.txt");
+/// assert_eq!(phargs::substitute("{}-{}", "{}"), "{}-{}");
+/// assert_eq!(phargs::substitute("{:4}-{4:}", "20240622"), "2024-0622");
+/// assert_eq!(phargs::substitute("{x}", "file1"), "{x}");
+/// assert_eq!(phargs::substitute(r"\{}", "file1"), "{}");
+/// ```
+pub fn substitute(template: &str, ph: &str) -> String {
+    Context {
+        ph,
+        ..Default::default()
+    }
+    .substitute(template)
+}
+
+/// Builds the `Command` that runs `program` with `args`, substituting `ph` in
+/// each of them as [`substitute`] does.
+///
+/// This is a shortcut for a single command; use [`PhCommandVec`] for a batch,
+/// `{#}` and `[..]` arguments.
+///
+/// # Examples
+///
+/// ```
+/// let command = phargs::build_command("echo", &["{}".to_string(), "b".to_string()], "a");
+/// assert_eq!(command.get_program(), "echo");
+/// assert_eq!(command.get_args().collect::<Vec<_>>(), ["a", "b"]);
+/// ```
+pub fn build_command(program: &str, args: &[String], ph: &str) -> std::process::Command {
+    let mut command = std::process::Command::new(substitute(program, ph));
+    command.args(args.iter().map(|arg| substitute(arg, ph)));
+    command
+}
+
+/// Returns true if `template` contains any placeholder recognized by a command.
+///
+/// Besides those of [`substitute`], this includes `{#}`.
+pub fn has_placeholder(template: &str) -> bool {
+    Context {
+        seq: Some(1),
+        ..Default::default()
+    }
+    .has_placeholder(template)
+}
+
+/// A piece of a parsed argument template, see [`parse_template`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    /// Text copied as is, with escapes already resolved.
+    Literal(String),
+    /// `{}` for the whole pharg, or `{N}` for its field `N`.
+    Placeholder { index: Option<usize> },
+    /// A whole `[..]` argument, with the format between the brackets.
+    Bracket { fmt: String },
+    /// Any other placeholder, such as `{#}`, `{abs}`, `{2:4}` or a whole
+    /// `{*}` argument, named by the text between its braces.
+    Special { kind: String },
+}
+
+/// Parses an argument template into tokens, for tools such as editors.
+///
+/// It walks the template with the same scanner as the substitution, so with a
+/// field delimiter set each placeholder token stands for one substitution. The
+/// substitution itself doesn't build tokens, only the scanner is shared.
+///
+/// A bracket argument is a single [`Token::Bracket`]. Otherwise adjacent text
+/// is merged into one [`Token::Literal`]. A field `{N}` is reported as
+/// [`Token::Placeholder`] even though the substitution leaves it literal
+/// without [`PhCommandVec::field_delimiter`]. Names of [`PhCommandVec::named`]
+/// lists are not known here and stay literal.
+///
+/// # Examples
+///
+/// ```
+/// use phargs::Token;
+///
+/// assert_eq!(
+///     phargs::parse_template("a{}b"),
+///     [
+///         Token::Literal("a".to_string()),
+///         Token::Placeholder { index: None },
+///         Token::Literal("b".to_string()),
+///     ]
+/// );
+/// ```
+pub fn parse_template(arg: &str) -> Vec<Token> {
+    if arg == "{*}" {
+        return vec![Token::Special {
+            kind: "*".to_string(),
+        }];
+    }
+    if let Some(fmt) = parse_bracket(arg) {
+        return vec![Token::Bracket {
+            fmt: fmt.to_string(),
+        }];
+    }
+    let ctx = Context {
+        seq: Some(1),
+        line: Some(1),
+        ..Default::default()
+    };
+    scan(arg, |token| {
+        let token = match token {
+            "" => Token::Placeholder { index: None },
+            _ if token.bytes().all(|b| b.is_ascii_digit()) => Token::Placeholder {
+                index: Some(token.parse().ok()?),
+            },
+            _ => {
+                ctx.expand(token)?;
+                Token::Special {
+                    kind: token.to_string(),
+                }
+            }
+        };
+        Some(vec![token])
+    })
+    .0
+}
+
+/// The values placeholders of a single command are substituted with.
+#[derive(Clone, Copy, Default)]
+struct Context<'a> {
+    ph: &'a str,
+    /// The counter value for `{#}`.
+    seq: Option<i64>,
+    /// The field delimiter for `{N}`.
+    delimiter: Option<&'a str>,
+    /// The names of the lists for `{name}`, and their values for this command.
+    names: &'a [String],
+    row: &'a [String],
+    /// The line number for `{.line}`.
+    line: Option<usize>,
+    /// A field `{N}` replaced by one item of its sublist, see [`Context::sublist`].
+    item: Option<(usize, &'a str)>,
+    /// The directory for `{rel}`, or the current directory if `None`.
+    base: Option<&'a str>,
+    /// Whether only the text after the first `=` is substituted, see
+    /// [`PhCommandVec::value_only`].
+    value_only: bool,
+}
+
+impl Context<'_> {
+    fn substitute(&self, template: &str) -> String {
+        self.substitute_counted(template).0
+    }
+
+    /// Substitutes `template` and counts the placeholders that were replaced.
+    fn substitute_counted(&self, template: &str) -> (String, usize) {
+        if self.value_only {
+            let Some((key, value)) = template.split_once('=') else {
+                return (template.to_string(), 0);
+            };
+            let (value, count) = scan(value, |token| self.expand(token));
+            return (format!("{}={}", key, value), count);
+        }
+        scan(template, |token| self.expand(token))
+    }
+
+    fn has_placeholder(&self, template: &str) -> bool {
+        self.substitute_counted(template).1 > 0
+    }
+
+    /// Substitutes the `[..]` argument `template` for one pharg. Its values
+    /// become literal pieces, and the rest of `template` is left to
+    /// substitute for each command.
+    fn substitute_arg(&self, template: &str) -> Arg {
+        if self.value_only {
+            let Some((key, value)) = template.split_once('=') else {
+                return Arg(vec![Part::Literal(template.to_string())]);
+            };
+            let mut arg = Arg(vec![Part::Literal(format!("{}=", key))]);
+            arg.append(
+                Context {
+                    value_only: false,
+                    ..*self
+                }
+                .substitute_arg(value),
+            );
+            return arg;
+        }
+        scan(template, |token| {
+            self.expand(token)
+                .map(|value| Arg(vec![Part::Literal(value)]))
+        })
+        .0
+    }
+
+    /// Substitutes the template pieces of `arg` and concatenates them with
+    /// the literal ones, counting the placeholders that were replaced.
+    fn assemble(&self, arg: &Arg) -> (String, usize) {
+        let ctx = Context {
+            value_only: false,
+            ..*self
+        };
+        let mut out = String::new();
+        let mut count = 0;
+        for part in &arg.0 {
+            match part {
+                Part::Template(template) => {
+                    let (s, n) = ctx.substitute_counted(template);
+                    out.push_str(&s);
+                    count += n;
+                }
+                Part::Literal(s) => out.push_str(s),
+            }
+        }
+        (out, count)
+    }
+
+    fn expand(&self, token: &str) -> Option<String> {
+        match token {
+            "" => Some(self.ph.to_string()),
+            "#" => self.seq.map(|n| n.to_string()),
+            "#fields" => self
+                .delimiter
+                .map(|delimiter| self.ph.split(delimiter).count().to_string()),
+            ".line" => self.line.map(|n| n.to_string()),
+            _ if token.bytes().all(|b| b.is_ascii_digit()) => self.field(token.parse().ok()?),
+            _ if self.names.iter().any(|n| n == token) => {
+                let i = self.names.iter().position(|n| n == token)?;
+                Some(self.row.get(i).cloned().unwrap_or_default())
+            }
+            "abs" => Some(match std::fs::canonicalize(self.ph) {
+                Ok(path) => path.to_string_lossy().into_owned(),
+                Err(_) => self.ph.to_string(),
+            }),
+            "size" | "mtime" => Some(self.metadata(token)),
+            "rel" => Some(self.relative()),
+            "hash" => Some(format!("{:08x}", fnv1a32(self.ph.as_bytes()))),
+            "safe" => Some(sanitize(self.ph)),
+            "url" => Some(percent_encode(self.ph)),
+            _ => match self.delimiter {
+                Some(delimiter) => self.field_range(token, delimiter),
+                None => slice(self.ph, token),
+            },
+        }
+    }
+
+    /// Substitutes `template` once per item of its first field `{N}`, split
+    /// by `sub_delimiter`. Without a field, `template` is substituted once.
+    fn sublist(&self, template: &str, sub_delimiter: &str) -> Vec<Arg> {
+        let mut first = None;
+        scan(template, |token| {
+            if first.is_none() && token.bytes().all(|b| b.is_ascii_digit()) {
+                first = token
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|_| self.delimiter.is_some());
+            }
+            None::<String>
+        });
+        let Some(n) = first else {
+            return vec![self.substitute_arg(template)];
+        };
+        let field = self.field(n).unwrap_or_default();
+        field
+            .split(sub_delimiter)
+            .map(|item| {
+                Context {
+                    item: Some((n, item)),
+                    ..*self
+                }
+                .substitute_arg(template)
+            })
+            .collect()
+    }
+
+    /// Returns the tokens of `template` that don't resolve for the pharg:
+    /// fields `{N}` it doesn't have and, with `strict_metadata`, `{size}` and
+    /// `{mtime}` of a path that doesn't exist.
+    fn unresolved(&self, template: &str, strict_metadata: bool) -> Vec<String> {
+        let fields = self.delimiter.map(|d| self.ph.split(d).count());
+        let mut unresolved = Vec::new();
+        scan(template, |token| {
+            let missing = match token {
+                "size" | "mtime" => strict_metadata && std::fs::metadata(self.ph).is_err(),
+                _ if token.bytes().all(|b| b.is_ascii_digit()) => {
+                    fields.is_some_and(|fields| token.parse::<usize>().is_ok_and(|n| n > fields))
+                }
+                _ => false,
+            };
+            if missing {
+                unresolved.push(format!("{{{}}}", token));
+            }
+            None::<String>
+        });
+        unresolved
+    }
+
+    /// Returns the pharg as a path relative to the base directory, or as is
+    /// if it isn't under that directory.
+    fn relative(&self) -> String {
+        let base = match self.base {
+            Some(base) => std::path::PathBuf::from(base),
+            None => match std::env::current_dir() {
+                Ok(dir) => dir,
+                Err(_) => return self.ph.to_string(),
+            },
+        };
+        match std::path::Path::new(self.ph).strip_prefix(&base) {
+            Ok(path) if path.as_os_str().is_empty() => ".".to_string(),
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(_) => self.ph.to_string(),
+        }
+    }
+
+    /// Returns the size or modification time of the pharg as a path, see [`substitute`].
+    fn metadata(&self, token: &str) -> String {
+        let Ok(metadata) = std::fs::metadata(self.ph) else {
+            return "0".to_string();
+        };
+        let value = match token {
+            "size" => metadata.len(),
+            _ => metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs()),
+        };
+        value.to_string()
+    }
+
+    /// Expands a `start:end` token to the 1-based fields `start..=end` of the
+    /// pharg, joined by `delimiter`.
+    fn field_range(&self, token: &str, delimiter: &str) -> Option<String> {
+        let (start, end) = parse_range(token)?;
+        let start = start.unwrap_or(1).max(1);
+        let len = end.map_or(usize::MAX, |end| (end + 1).saturating_sub(start));
+        let fields = self.ph.split(delimiter).skip(start - 1).take(len);
+        Some(fields.collect::<Vec<_>>().join(delimiter))
+    }
+
+    /// Returns the 1-based field `n` of the pharg, or an empty string if missing.
+    fn field(&self, n: usize) -> Option<String> {
+        if let Some((_, item)) = self.item.filter(|(m, _)| *m == n) {
+            return Some(item.to_string());
+        }
+        let delimiter = self.delimiter?;
+        let index = n.checked_sub(1)?;
+        Some(
+            self.ph
+                .split(delimiter)
+                .nth(index)
+                .unwrap_or("")
+                .to_string(),
+        )
+    }
+}
+
+/// A string type [`scan`] can build.
+trait ScanOutput: Sized {
+    fn with_capacity(capacity: usize) -> Self;
+    fn push_str(&mut self, s: &str);
+    fn append(&mut self, s: Self);
+    /// Pushes an escaped placeholder, without its escaping backslash.
+    fn push_escaped(&mut self, s: &str) {
+        self.push_str(s)
+    }
+}
+
+impl ScanOutput for String {
+    fn with_capacity(capacity: usize) -> Self {
+        String::with_capacity(capacity)
+    }
+    fn push_str(&mut self, s: &str) {
+        self.push_str(s)
+    }
+    fn append(&mut self, s: Self) {
+        self.push_str(&s)
+    }
+}
+
+impl ScanOutput for std::ffi::OsString {
+    fn with_capacity(capacity: usize) -> Self {
+        std::ffi::OsString::with_capacity(capacity)
+    }
+    fn push_str(&mut self, s: &str) {
+        self.push(s)
+    }
+    fn append(&mut self, s: Self) {
+        self.push(s)
+    }
+}
+
+impl ScanOutput for Arg {
+    fn with_capacity(_: usize) -> Self {
+        Arg::default()
+    }
+    fn push_str(&mut self, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        match self.0.last_mut() {
+            Some(Part::Template(template)) => template.push_str(s),
+            _ => self.0.push(Part::Template(s.to_string())),
+        }
+    }
+    fn append(&mut self, s: Self) {
+        self.0.extend(s.0)
+    }
+    fn push_escaped(&mut self, s: &str) {
+        self.0.push(Part::Literal(s.to_string()))
+    }
+}
+
+impl ScanOutput for Vec<Token> {
+    fn with_capacity(_: usize) -> Self {
+        Vec::new()
+    }
+    fn push_str(&mut self, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        match self.last_mut() {
+            Some(Token::Literal(literal)) => literal.push_str(s),
+            _ => self.push(Token::Literal(s.to_string())),
+        }
+    }
+    fn append(&mut self, s: Self) {
+        self.extend(s)
+    }
+}
+
+/// Walks `template` and replaces each `{token}` for which `expand` returns a value.
+///
+/// A token preceded by an odd number of backslashes is escaped: it is kept
+/// literally, and each pair of backslashes before a token becomes one. The
+/// escaped value of `expand` is dropped.
+///
+/// # Returns
+///
+/// Returns the result and the number of tokens replaced.
+fn scan<T: ScanOutput>(template: &str, mut expand: impl FnMut(&str) -> Option<T>) -> (T, usize) {
+    let mut out = T::with_capacity(template.len());
+    let mut count = 0;
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let Some((end, s)) = after
+            .find('}')
+            .and_then(|end| expand(&after[..end]).map(|s| (end, s)))
+        else {
+            out.push_str(&rest[..=start]);
+            rest = after;
+            continue;
+        };
+        let text = &rest[..start];
+        let backslashes = text.len() - text.trim_end_matches('\\').len();
+        out.push_str(&text[..text.len() - backslashes]);
+        out.push_str(&text[text.len() - backslashes / 2..]);
+        if backslashes % 2 == 1 {
+            out.push_escaped(&rest[start..start + end + 2]);
+        } else {
+            out.append(s);
+            count += 1;
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    (out, count)
+}
+
+/// Parses a `start:end` token into its bounds, `None` for an omitted one.
+fn parse_range(token: &str) -> Option<(Option<usize>, Option<usize>)> {
+    let (start, end) = token.split_once(':')?;
+    let bound = |s: &str| {
+        if s.is_empty() {
+            Some(None)
+        } else {
+            s.parse::<usize>().ok().map(Some)
+        }
+    };
+    Some((bound(start)?, bound(end)?))
+}
+
+/// Expands a `start:end` token to the chars of `ph` in that range.
+fn slice(ph: &str, token: &str) -> Option<String> {
+    let (start, end) = parse_range(token)?;
+    let start = start.unwrap_or(0);
+    let len = end.map_or(usize::MAX, |end| end.saturating_sub(start));
+    Some(ph.chars().skip(start).take(len).collect())
+}
+
+/// Hashes `bytes` with 32-bit FNV-1a.
+fn fnv1a32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Replaces the characters of `ph` that are unsafe in file names with `_`.
+fn sanitize(ph: &str) -> String {
+    ph.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Percent-encodes the UTF-8 bytes of `ph` other than the unreserved
+/// characters of RFC 3986.
+fn percent_encode(ph: &str) -> String {
+    let mut out = String::with_capacity(ph.len());
+    for b in ph.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(char::from(b));
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Extends a row of format strings into a flat list of formatted strings.
+///
+/// This function takes a collection of format strings, some of which may include bracketed placeholders,
+/// and flattens the result after applying each placeholder substitution.
+///
+/// # Arguments
+///
+/// * `row` - An iterable collection of format strings.
+/// * `args` - An array of strings to substitute into format strings.
+///
+/// # Returns
+///
+/// Returns a flattened vector of strings after substituting placeholders.
+///
+/// # Examples
+///
+/// ```
+/// let formats = vec!["plain text", "[{}.txt]"];
+/// let args = vec!["file1", "file2"];
+/// let extended = phargs::extend_row(formats, &args);
+/// assert_eq!(extended, vec!["plain text", "file1.txt", "file2.txt"]);
+/// ```
+pub fn extend_row<'r, 'a, R: AsRef<str> + 'r + ?Sized, A: AsRef<str> + 'a>(
+    row: impl IntoIterator<Item = &'r R>,
+    args: &'a [A],
+) -> Vec<String> {
+    let config = Config::default();
+    extend_row_in(row, args, &config)
+        .into_iter()
+        .map(Arg::join)
+        .collect()
+}
+
+/// Same as [`extend_row`], but with fields split by `delimiter` as in
+/// [`extend_array_fields`].
+///
+/// # Examples
+///
+/// ```
+/// let extended = phargs::extend_row_fields(["-o", "[{1}.{2}]"], &["a:txt"], ":");
+/// assert_eq!(extended, vec!["-o", "a.txt"]);
+/// ```
+pub fn extend_row_fields<'r, 'a, R: AsRef<str> + 'r + ?Sized, A: AsRef<str> + 'a>(
+    row: impl IntoIterator<Item = &'r R>,
+    args: &'a [A],
+    delimiter: &str,
+) -> Vec<String> {
+    let config = Config {
+        delimiter: Some(delimiter.to_string()),
+        ..Default::default()
+    };
+    extend_row_in(row, args, &config)
+        .into_iter()
+        .map(Arg::join)
+        .collect()
+}
+
+/// A limit for [`try_extend_row`] that is far above any practical argv.
+pub const DEFAULT_MAX_EXPANSIONS: usize = 1 << 20;
+
+/// Same as [`extend_row`], but fails instead of producing more than
+/// `max_expansions` strings, which protects against untrusted templates. The
+/// count is checked before any string is built.
+///
+/// # Errors
+///
+/// Returns an `InvalidInput` error if the row expands to too many strings.
+///
+/// # Examples
+///
+/// ```
+/// let row = phargs::try_extend_row(["[{}]"], &["a", "b"], phargs::DEFAULT_MAX_EXPANSIONS);
+/// assert_eq!(row.unwrap(), ["a", "b"]);
+/// assert!(phargs::try_extend_row(["[{}]"], &["a", "b"], 1).is_err());
+/// ```
+pub fn try_extend_row<'r, 'a, R: AsRef<str> + 'r + ?Sized, A: AsRef<str> + 'a>(
+    row: impl IntoIterator<Item = &'r R>,
+    args: &'a [A],
+    max_expansions: usize,
+) -> std::io::Result<Vec<String>> {
+    let config = Config::default();
+    let row = row.into_iter().map(AsRef::as_ref).collect::<Vec<_>>();
+    check_expansions(row.iter().copied(), args.len(), &config, max_expansions)?;
+    Ok(extend_row_in(row, args, &config)
+        .into_iter()
+        .map(Arg::join)
+        .collect())
+}
+
+/// Fails if `row` expands to more than `max_expansions` strings with
+/// `phargs` phargs.
+fn check_expansions<'r>(
+    row: impl IntoIterator<Item = &'r str>,
+    phargs: usize,
+    config: &Config,
+    max_expansions: usize,
+) -> std::io::Result<()> {
+    let count = row
+        .into_iter()
+        .map(|fmt| config.expansion_count(fmt, phargs))
+        .fold(0, usize::saturating_add);
+    if count > max_expansions {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("template expands to more than {} arguments", max_expansions),
+        ));
+    }
+    Ok(())
+}
+
+/// Extends `row` into a vector sized up front, so that large rows are built
+/// without reallocating.
+fn extend_row_in<'r, R: AsRef<str> + 'r + ?Sized, A: AsRef<str>>(
+    row: impl IntoIterator<Item = &'r R>,
+    args: &[A],
+    config: &Config,
+) -> Vec<Arg> {
+    let row = row.into_iter().map(AsRef::as_ref).collect::<Vec<_>>();
+    let capacity = row
+        .iter()
+        .map(|fmt| config.expansion_count(fmt, args.len()))
+        .sum();
+    let mut out = Vec::with_capacity(capacity);
+    for fmt in row {
+        extend_array_into(fmt, args.iter(), config, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comma_separated() {
+        assert_eq!(comma_separated("a,b,c"), vec!["a", "b", "c"]);
+        for s in ["a,b,c", "", ",a,"] {
+            let borrowed = CommaSeparated::new(s).into_iter().collect::<Vec<&str>>();
+            assert_eq!(borrowed, comma_separated(s));
+        }
+    }
+
+    #[test]
+    fn test_comma_separated_escaped() {
+        assert_eq!(comma_separated_escaped("a,b,c"), comma_separated("a,b,c"));
+        assert_eq!(comma_separated_escaped(r"a\,b,c"), ["a,b", "c"]);
+        assert_eq!(comma_separated_escaped(r"a\\,b"), [r"a\", "b"]);
+        assert_eq!(comma_separated_escaped(r"a\\\,b"), [r"a\,b"]);
+        assert_eq!(comma_separated_escaped(r"a,b\"), ["a", r"b\"]);
+        assert_eq!(comma_separated_escaped(r"C:\dir"), [r"C:\dir"]);
+        assert_eq!(comma_separated_escaped(""), [""]);
+    }
+
+    #[test]
+    fn test_program_from_arg0() {
+        assert_eq!(program_from_arg0("A", "/a"), "/A");
+        assert_eq!(program_from_arg0("A", "/b/c/a"), "/b/c/A");
+        assert_eq!(program_from_arg0("A", "b/c/a"), "b/c/A");
+        assert_eq!(program_from_arg0("A", "./c/a"), "./c/A");
+        assert_eq!(program_from_arg0("A", "../c/a"), "../c/A");
+        assert_eq!(program_from_arg0("A", "a"), "A");
+    }
+
+    #[test]
+    fn test_find_program_from_arg0() {
+        let dir = std::env::temp_dir().join(format!("phargs-sibling-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("tool");
+        std::fs::write(&tool, "").unwrap();
+        let arg0 = dir.join("phargs");
+        let arg0 = arg0.to_str().unwrap();
+
+        assert_eq!(find_program_from_arg0("tool", arg0), tool.to_str().unwrap());
+        assert_eq!(find_program_from_arg0("other", arg0), "other");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_program() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("phargs-resolve-{}", std::process::id()));
+        let bin = dir.join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        for path in [dir.join("tool"), bin.join("tool"), bin.join("other")] {
+            std::fs::write(&path, "").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let arg0 = dir.join("phargs");
+        let arg0 = arg0.to_str().unwrap();
+        let paths = Some(bin.as_os_str());
+
+        // Next to arg0 first, then in PATH.
+        let resolved = resolve_program_in("tool", arg0, paths);
+        assert_eq!(resolved, dir.join("tool").to_str().unwrap());
+        let resolved = resolve_program_in("other", arg0, paths);
+        assert_eq!(resolved, bin.join("other").to_str().unwrap());
+        assert_eq!(resolve_program_in("missing", arg0, paths), "missing");
+        assert_eq!(resolve_program_in("other", arg0, None), "other");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("phargs-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("phargs-path-tool");
+        std::fs::write(&tool, "#!/bin/sh\nexit 7\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let pcv = PhCommandVec::new("phargs-path-tool", vec!["{}"], vec!["a"])
+            .path(dir.to_str().unwrap());
+        let status = pcv.iter().next().unwrap().status().unwrap();
+        assert_eq!(status.code(), Some(7));
+        assert!(pcv.missing_programs().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::useless_vec)]
+    fn test_rows() {
+        assert_eq!(
+            extend_array("[{}.txt]", &vec!["a", "b"]),
+            vec!["a.txt", "b.txt"]
+        );
+        assert_eq!(
+            extend_array("{}.txt", vec!["a".to_string(), "b".to_string()].iter()),
+            vec!["{}.txt"]
+        );
+    }
+
+    #[test]
+    fn test_parse_bracket() {
+        assert_eq!(parse_bracket("[x]"), Some("x"));
+        assert_eq!(parse_bracket("x"), None);
+        assert_eq!(parse_bracket("[]"), Some(""));
+        assert_eq!(parse_bracket("["), None);
+        assert_eq!(parse_bracket("[x"), None);
+        assert_eq!(parse_bracket("x]"), None);
+        assert_eq!(parse_bracket("{*}"), None);
+        assert_eq!(bracket_format("{*}"), Some("{}"));
+        assert_eq!(bracket_format("{*}.txt"), None);
+        assert_eq!(bracket_format("[x]"), Some("x"));
+
+        let pcv = PhCommandVec::new("echo", vec!["{*}"], vec!["a", "b", "c"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo a b c"]);
+        let pcv = PhCommandVec::new("echo", vec!["-x", "{*}", "--"], vec!["a", "b"]);
+        assert_eq!(pcv.iter().next().unwrap().args(), ["-x", "a", "b", "--"]);
+        let pcv = PhCommandVec::new("echo", vec!["x{*}"], vec!["a", "b"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo x{*}"]);
+    }
+
+    #[test]
+    #[allow(clippy::useless_vec)]
+    fn test_row_has_ph() {
+        assert!(row_has_ph(&vec!["a", "{}"]));
+        assert!(row_has_ph(&vec!["a", "{}.txt"]));
+        assert!(!row_has_ph(&vec!["a", "b", "c"]));
+        let v = vec!["a".to_string(), "b".to_string()];
+        assert!(!row_has_ph(v.iter()));
+        assert!(!row_has_ph(v.iter()));
+    }
+
+    #[test]
+    #[allow(clippy::useless_vec)]
+    fn test_extend_row() {
+        assert_eq!(
+            extend_row(["a", "[{}.txt]"], &["1", "2"]),
+            ["a", "1.txt", "2.txt"]
+        );
+        assert_eq!(
+            extend_row(
+                vec!["a".to_string(), "[{}.txt]".to_string()].iter(),
+                &vec!["1".to_string(), "2".to_string()]
+            ),
+            vec!["a", "1.txt", "2.txt"]
+        );
+    }
+
+    #[test]
+    fn test_try_extend_row() {
+        let args = ["1", "2", "3"];
+        let row = ["a", "[{}.x]", "[{}.y]"];
+        assert_eq!(
+            try_extend_row(row, &args, 7).unwrap(),
+            extend_row(row, &args)
+        );
+        let err = try_extend_row(row, &args, 6).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let config = Config {
+            brace_sets: true,
+            ..Default::default()
+        };
+        assert_eq!(config.expansion_count("[{}.{x,y,z}]", 4), 12);
+        assert_eq!(config.expansion_count("a.{x,y}", 4), 2);
+        assert_eq!(config.expansion_count("[{}]", 0), 0);
+        assert_eq!(
+            Config::default().expansion_count("[{}]", usize::MAX),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn test_max_expansions() {
+        let pcv = PhCommandVec::new("echo", vec!["a", "[{}.x]", "[{}.y]"], vec!["1", "2", "3"]);
+        assert!(pcv.check_expansions().is_ok());
+        let pcv = pcv.max_expansions(7);
+        assert!(pcv.check_expansions().is_ok());
+        assert_eq!(pcv.len(), 1);
+
+        let pcv = pcv.max_expansions(6);
+        let err = pcv.check_expansions().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(pcv.len(), 0);
+        assert_eq!(pcv.iter().count(), 0);
+
+        // The limit counts alternatives, and holds for per-pharg commands too.
+        let pcv = PhCommandVec::new("echo", vec!["{}", "[{}.{x,y}]"], vec!["1", "2"])
+            .brace_sets(true)
+            .max_expansions(4);
+        assert!(pcv.check_expansions().is_err());
+        assert_eq!(pcv.iter().count(), 0);
+        assert_eq!(pcv.max_expansions(5).iter().count(), 2);
+    }
+
+    #[test]
+    fn test_brace_set() {
+        let config = Config {
+            brace_sets: true,
+            ..Default::default()
+        };
+        let extend = |row: &[&str], args: &[&str]| {
+            extend_row_in(row, args, &config)
+                .into_iter()
+                .map(Arg::join)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(extend(&["a.{x,y}"], &["1"]), ["a.x", "a.y"]);
+        assert_eq!(extend(&["a.x"], &["1"]), ["a.x"]);
+        assert_eq!(extend(&["{}.{x,y}"], &["1"]), ["{}.x", "{}.y"]);
+        assert_eq!(
+            extend(&["[{}.{x,y}]"], &["1", "2"]),
+            ["1.x", "1.y", "2.x", "2.y"]
+        );
+        assert_eq!(extend(&[r"\{a,b}"], &[]), ["{a,b}"]);
+        assert_eq!(extend(&[r"\\{a,b}"], &[]), [r"\a", r"\b"]);
+        assert_eq!(extend(&[r"x\{a,b}.{c,d}"], &[]), ["x{a,b}.c", "x{a,b}.d"]);
+    }
+
+    #[test]
+    fn test_brace_set_disabled() {
+        let json = r#"{"a":1,"b":2}"#;
+        assert_eq!(extend_row([json, "a.{x,y}"], &["1"]), [json, "a.{x,y}"]);
+        let pcv = PhCommandVec::new("curl", vec!["-d", json, "{}"], vec!["url"]);
+        assert_eq!(pcv.iter().next().unwrap().args(), ["-d", json, "url"]);
+        let pcv = PhCommandVec::new("awk", vec!["{print $1,$2}", "{}"], vec!["f"]);
+        assert_eq!(pcv.iter().next().unwrap().args(), ["{print $1,$2}", "f"]);
+    }
+
+    #[test]
+    fn test_extend_row_capacity() {
+        let args = (0..1000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let row = ["a", "[{}.txt]", "b", "[-{}]"];
+        let extended = extend_row(row, &args);
+        assert_eq!(extended.len(), 2002);
+        assert_eq!(extended.capacity(), extended.len());
+        assert_eq!(
+            extended,
+            row.iter()
+                .flat_map(|s| extend_array(s, &args))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_pharg_inserted_literally() {
+        let pcv = PhCommandVec::new("echo", vec!["{}", "x{}"], vec!["{}", "{#}"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo {} x{}", "echo {#} x{#}"]);
+
+        // Values of `[..]` arguments aren't substituted again for each command.
+        let pcv = PhCommandVec::new("echo", vec!["[{}]", "{}"], vec!["{}", "{#}", "x"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            ["echo {} {#} x {}", "echo {} {#} x {#}", "echo {} {#} x x"]
+        );
+        let pcv = PhCommandVec::new("echo", vec!["[{}-{#}]"], vec!["{}", "{#}"]);
+        assert_eq!(pcv.first().unwrap(), "echo {}-1 {#}-1");
+        assert_eq!(pcv.len(), 2);
+        let pcv = PhCommandVec::new("echo", vec!["[{1}]"], vec!["{2},b"])
+            .field_delimiter(",")
+            .sub_delimiter(":");
+        assert_eq!(pcv.first().unwrap(), "echo {2}");
+        let pcv = PhCommandVec::new("run", vec!["[k={}]", "{}"], vec!["{}"]).value_only(true);
+        assert_eq!(pcv.first().unwrap(), "run k={} {}");
+    }
+
+    #[test]
+    fn test_ph_command() {
+        let pc = PhCommand {
+            program: "echo",
+            args: &[Arg::template("{}", false), Arg::template("b", false)],
+            ph: "a".to_string(),
+            seq: 1,
+            row: &[],
+            line: None,
+            config: &Config::default(),
+        };
+        assert_eq!(pc.args(), vec!["a", "b"]);
+        assert_eq!(pc.command_string(), "echo a b");
+        assert_eq!(pc.command_string_with(", "), "echo, a, b");
+    }
+
+    #[test]
+    fn test_programs() {
+        let pcv = PhCommandVec::programs(vec!["tool-a", "tool-b"], vec!["{}"], vec!["x", "y", "z"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["tool-a x", "tool-b y", "tool-a z"]);
+        assert_eq!(pcv.program(), "tool-a");
+
+        let pcv =
+            PhCommandVec::programs(vec!["a", "{}-b", "c"], Vec::<String>::new(), vec!["1", "2"]);
+        assert!(pcv.args_has_placeholder());
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["a", "2-b"]);
+    }
+
+    #[test]
+    fn test_try_new() {
+        let err = PhCommandVec::try_new("", vec!["{}"], vec!["a"])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "program is empty");
+
+        let pcv = PhCommandVec::try_new("echo", vec!["{}"], vec!["a"]).unwrap();
+        assert_eq!(pcv.first().unwrap(), "echo a");
+        // The program may still be a template that expands to a name.
+        assert!(PhCommandVec::try_new("{}", Vec::<String>::new(), vec!["true"]).is_ok());
+    }
+
+    #[test]
+    fn test_ph_command_vec() {
+        let pcv = PhCommandVec::new(
+            "echo".to_string(),
+            vec!["{}".to_string(), "b".to_string()],
+            vec!["a".to_string(), "c".to_string()],
+        );
+        let mut iter = pcv.iter();
+        assert_eq!(iter.next().unwrap().command_string(), "echo a b");
+        assert_eq!(iter.next().unwrap().command_string(), "echo c b");
+        assert!(iter.next().is_none());
+
+        let pcv = PhCommandVec::new("echo", vec!["[{}.txt]", "b"], vec!["a", "c"]);
+        let mut iter = pcv.iter();
+        assert_eq!(iter.next().unwrap().command_string(), "echo a.txt c.txt b");
+        assert!(iter.next().is_none());
+
+        let pcv = PhCommandVec::new("echo", vec!["[{}.txt]", "{}"], vec!["a", "c"]);
+        let mut iter = pcv.iter();
+        assert_eq!(iter.next().unwrap().command_string(), "echo a.txt c.txt a");
+        assert_eq!(iter.next().unwrap().command_string(), "echo a.txt c.txt c");
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_wrap() {
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["a"]).wrap(vec!["nice", "time"]);
+        let command = pcv.iter().next().unwrap();
+        assert_eq!(command.argv(), ["nice", "time", "echo", "a"]);
+        assert_eq!(command.program(), "echo");
+        assert_eq!(pcv.to_vec()[0].program(), "nice");
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        let phargs = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+        let pcv = PhCommandVec::new("echo", vec!["{}"], phargs);
+        let chunks = pcv.split_chunks(3);
+        assert_eq!(
+            chunks.iter().map(PhCommandVec::len).collect::<Vec<_>>(),
+            [3, 3, 4]
+        );
+        let union = chunks
+            .iter()
+            .flat_map(|c| c.to_vec())
+            .map(|c| c.command_string())
+            .collect::<Vec<_>>();
+        let all = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(union, all);
+        assert!(pcv.split_chunks(20)[0].is_empty());
+    }
+
+    #[test]
+    fn test_args_has_placeholder() {
+        assert!(PhCommandVec::new("echo", vec!["{}"], vec!["x"]).args_has_placeholder());
+        assert!(!PhCommandVec::new("echo", vec!["a", "b"], vec!["x"]).args_has_placeholder());
+        assert!(!PhCommandVec::new("echo", vec!["[{}]"], vec!["x"]).args_has_placeholder());
+    }
+
+    #[test]
+    fn test_first() {
+        let pcv = PhCommandVec::new("echo", vec!["{}.txt"], vec!["a", "b"]);
+        assert_eq!(pcv.first().as_deref(), Some("echo a.txt"));
+        assert_eq!(pcv.iter().count(), 2);
+        let pcv = PhCommandVec::new("echo", vec!["{}"], Vec::<String>::new());
+        assert_eq!(pcv.first(), None);
+    }
+
+    #[test]
+    fn test_skip_header() {
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["name", "a", "b"]).skip_header(true);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo a", "echo b"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["[{}]"], vec!["name", "a"]).skip_header(true);
+        assert_eq!(pcv.first().as_deref(), Some("echo a"));
+
+        let pcv = PhCommandVec::new("echo", vec!["{}"], Vec::<String>::new()).skip_header(true);
+        assert!(pcv.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_response_file_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("phargs-rsp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stub = dir.join("stub");
+        std::fs::write(&stub, "#!/bin/sh\ncat \"${1#@}\"\n").unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let pcv = PhCommandVec::new(
+            stub.to_str().unwrap(),
+            vec!["-o", "{}.txt", "a \"b\""],
+            vec!["x"],
+        );
+        let response = dir.join("args.rsp");
+        let output = pcv
+            .iter()
+            .next()
+            .unwrap()
+            .response_file_command(&response)
+            .unwrap()
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "-o\nx.txt\n\"a \\\"b\\\"\"\n"
+        );
+        let err = pcv
+            .iter()
+            .next()
+            .unwrap()
+            .response_file_command(&response)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "nice"))]
+    #[test]
+    fn test_nice() {
+        let pcv = PhCommandVec::new("sh", vec!["-c", "{}"], vec!["nice"]).nice(5);
+        let output = pcv.iter().next().unwrap().output().unwrap();
+        assert!(output.status.success());
+        let niceness = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<i32>();
+        assert!(niceness.unwrap() >= 5);
+
+        let owned = pcv.to_vec()[0].command().output().unwrap();
+        assert_eq!(owned.stdout, output.stdout);
+    }
+
+    #[test]
+    fn test_parse_template() {
+        let literal = |s: &str| Token::Literal(s.to_string());
+        assert_eq!(
+            parse_template("a{}b"),
+            [
+                literal("a"),
+                Token::Placeholder { index: None },
+                literal("b")
+            ]
+        );
+        assert_eq!(
+            parse_template("[{}.txt]"),
+            [Token::Bracket {
+                fmt: "{}.txt".to_string()
+            }]
+        );
+        assert_eq!(
+            parse_template("{*}"),
+            [Token::Special {
+                kind: "*".to_string()
+            }]
+        );
+        assert_eq!(parse_template("x{*}"), [literal("x{*}")]);
+        // `{.}` isn't a placeholder, so it is merged into the literal text.
+        assert_eq!(
+            parse_template("{1}-{.}"),
+            [Token::Placeholder { index: Some(1) }, literal("-{.}")]
+        );
+        assert_eq!(
+            parse_template(r"{#}\{}{x}{:3}"),
+            [
+                Token::Special {
+                    kind: "#".to_string()
+                },
+                literal("{}{x}"),
+                Token::Special {
+                    kind: ":3".to_string()
+                },
+            ]
+        );
+        assert_eq!(parse_template(""), []);
+    }
+
+    #[test]
+    fn test_parse_template_matches_substitution() {
+        let ctx = Context {
+            ph: "a:b",
+            seq: Some(1),
+            delimiter: Some(":"),
+            line: Some(1),
+            ..Default::default()
+        };
+        for template in [
+            "a{}b",
+            "{1}-{.}",
+            r"{#}\{}{x}{:3}",
+            "{.line}{2}{abs}",
+            "{{}}",
+            "",
+        ] {
+            let placeholders = parse_template(template)
+                .iter()
+                .filter(|token| !matches!(token, Token::Literal(_)))
+                .count();
+            assert_eq!(
+                placeholders,
+                ctx.substitute_counted(template).1,
+                "{}",
+                template
+            );
+        }
+
+        // Without a delimiter `{N}` is still a token, but stays literal.
+        let ctx = Context {
+            delimiter: None,
+            ..ctx
+        };
+        assert_eq!(
+            parse_template("{1}"),
+            [Token::Placeholder { index: Some(1) }]
+        );
+        assert_eq!(ctx.substitute_counted("{1}"), ("{1}".to_string(), 0));
+    }
+
+    #[test]
+    fn test_keep_empty_brackets() {
+        let row = ["-o", "[{}.txt]", r"[\{}-{#}]"];
+        let config = Config::default();
+        let join = |args: Vec<Arg>| args.into_iter().map(Arg::join).collect::<Vec<_>>();
+        assert_eq!(join(extend_row_in(row, &[] as &[&str], &config)), ["-o"]);
+        let config = Config {
+            keep_empty_brackets: true,
+            ..Default::default()
+        };
+        let kept = extend_row_in(row, &[] as &[&str], &config);
+        let ctx = config.context("", Some(1));
+        let kept = kept.iter().map(|s| ctx.assemble(s).0).collect::<Vec<_>>();
+        assert_eq!(kept, ["-o", "{}.txt", r"\{}-{#}"]);
+        let args = extend_row_in(row, &["a"], &config);
+        let assembled = args.iter().map(|s| ctx.assemble(s).0).collect::<Vec<_>>();
+        assert_eq!(assembled, ["-o", "a.txt", "{}-1"]);
+        assert_eq!(join(args), ["-o", "a.txt", "{}-{#}"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["[{}.txt]"], Vec::<String>::new());
+        assert_eq!(pcv.iter().count(), 0);
+        let pcv = pcv.keep_empty_brackets(true);
+        assert_eq!(pcv.len(), 1);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo {}.txt"]);
+        let pcv = PhCommandVec::new("echo", vec!["{}"], Vec::<String>::new());
+        assert!(pcv.keep_empty_brackets(true).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_arg0() {
+        let pcv = PhCommandVec::new("sh", vec!["-c", "echo $0 {}"], vec!["a"]).arg0("phargs-sh");
+        let output = pcv.iter().next().unwrap().output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "phargs-sh a\n");
+        let output = pcv.to_vec()[0].command().output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "phargs-sh a\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_arg0_wrapped() {
+        // The wrapper prints its own argv[0], which must not be renamed.
+        let wrapper = vec!["sh", "-c", "tr '\\0' '\\n' < /proc/$$/cmdline | head -n 1"];
+        let pcv = PhCommandVec::new("true", vec!["{}"], vec!["a"])
+            .wrap(wrapper)
+            .arg0("phargs-sh");
+        let output = pcv.iter().next().unwrap().output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "sh\n");
+    }
+
+    #[test]
+    fn test_command_strings_quoted() {
+        let pcv = PhCommandVec::new("echo", vec!["-n", "{}"], vec!["a b", "it's", "c", ""]);
+        let quoted = pcv.command_strings_quoted();
+        assert_eq!(
+            quoted,
+            pcv.iter()
+                .map(|c| c.command_string_quoted())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            quoted,
+            [
+                "echo -n 'a b'",
+                r"echo -n 'it'\''s'",
+                "echo -n c",
+                "echo -n ''"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clear_env() {
+        let pcv = PhCommandVec::new("env", Vec::<String>::new(), vec!["a"]).clear_env(vec!["PATH"]);
+        let output = pcv.iter().next().unwrap().output().unwrap();
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("PATH={}\n", path)
+        );
+
+        let pcv = pcv.clear_env(Vec::<String>::new()).path("/bin:/usr/bin");
+        let output = pcv.iter().next().unwrap().output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "PATH=/bin:/usr/bin\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_current_dir() {
+        let root = std::env::temp_dir().join(format!("phargs-current-dir-{}", std::process::id()));
+        let dirs = ["a", "b"].map(|s| root.join(s));
+        for dir in &dirs {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let root = std::fs::canonicalize(&root).unwrap();
+        let pcv = PhCommandVec::new("pwd", Vec::<String>::new(), vec!["a", "b"])
+            .current_dir(root.join("{}").to_str().unwrap());
+        assert!(pcv.args_has_placeholder());
+        let outputs = pcv
+            .iter()
+            .map(|c| String::from_utf8(c.output().unwrap().stdout).unwrap())
+            .collect::<Vec<_>>();
+        let expected = ["a", "b"].map(|s| format!("{}\n", root.join(s).display()));
+        assert_eq!(outputs, expected);
+        let owned = pcv
+            .to_vec()
+            .iter()
+            .map(|c| String::from_utf8(c.command().output().unwrap().stdout).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(owned, expected);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_output_limited() {
+        let pcv = PhCommandVec::new(
+            "sh",
+            vec!["-c", "{}"],
+            vec!["seq 1000; seq 3 >&2", "echo ok"],
+        );
+        let mut iter = pcv.iter();
+        let command = iter.next().unwrap();
+        let (output, truncated) = command.output_limited(10).unwrap();
+        assert!(truncated);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"1\n2\n3\n4\n5\n");
+        assert_eq!(output.stderr, b"1\n2\n3\n");
+        let (status, output, truncated) = command.output_merged_limited(4).unwrap();
+        assert!(truncated && status.success());
+        assert_eq!(output.len(), 4);
+
+        let command = iter.next().unwrap();
+        let (output, truncated) = command.output_limited(10).unwrap();
+        assert!(!truncated);
+        assert_eq!(output.stdout, b"ok\n");
+        assert!(!command.output_merged_limited(3).unwrap().2);
+    }
+
+    #[test]
+    fn test_stdin_template() {
+        let pcv = PhCommandVec::new("cat", Vec::<String>::new(), vec!["a", "b"])
+            .stdin_template("payload-{}\n{#}");
+        let outputs = pcv
+            .iter()
+            .map(|c| String::from_utf8(c.output().unwrap().stdout).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, ["payload-a\n1", "payload-b\n2"]);
+        assert!(pcv.iter().next().unwrap().status().unwrap().success());
+
+        // A command that doesn't read its stdin still finishes.
+        let pcv =
+            PhCommandVec::new("true", vec!["{}"], vec!["a"]).stdin_template("x".repeat(1 << 20));
+        assert!(pcv.iter().next().unwrap().status().unwrap().success());
+    }
 
     #[test]
-    fn test_comma_separated() {
-        assert_eq!(comma_separated("a,b,c"), vec!["a", "b", "c"]);
+    fn test_stdin_template_paths() {
+        let pcv = PhCommandVec::new("cat", Vec::<String>::new(), vec!["a"]).stdin_template("in-{}");
+        let c = pcv.iter().next().unwrap();
+        assert_eq!(c.output_merged().unwrap().1, b"in-a");
+        assert_eq!(c.output_limited(4).unwrap().0.stdout, b"in-a");
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        c.stream_output(&mut out, &mut err).unwrap();
+        assert_eq!(out, b"in-a");
+        let mut out = Vec::new();
+        c.run_prefixed("{}: ", &mut out).unwrap();
+        assert_eq!(out, b"a: in-a");
+        let output = c.spawn_with_input(c.command().stdout(std::process::Stdio::piped()));
+        assert_eq!(output.unwrap().wait_with_output().unwrap().stdout, b"in-a");
+
+        let pcv = pcv.pipe_to("tr", vec!["a-z", "A-Z"]);
+        let c = pcv.iter().next().unwrap();
+        assert_eq!(c.output().unwrap().stdout, b"IN-A");
+        assert_eq!(c.output_merged().unwrap().1, b"IN-A");
+        let mut out = Vec::new();
+        c.run_prefixed("{}: ", &mut out).unwrap();
+        assert_eq!(out, b"a: IN-A");
     }
 
     #[test]
-    fn test_program_from_arg0() {
-        assert_eq!(program_from_arg0("A", "/a"), "/A");
-        assert_eq!(program_from_arg0("A", "/b/c/a"), "/b/c/A");
-        assert_eq!(program_from_arg0("A", "b/c/a"), "b/c/A");
-        assert_eq!(program_from_arg0("A", "./c/a"), "./c/A");
-        assert_eq!(program_from_arg0("A", "../c/a"), "../c/A");
-        assert_eq!(program_from_arg0("A", "a"), "A");
+    fn test_value_only() {
+        let args = vec!["key={}", "{}=x", "{}", "a={}={}", "[-D{}=on]"];
+        let pcv = PhCommandVec::new("run", args.clone(), vec!["v"]);
+        assert_eq!(pcv.first().unwrap(), "run key=v v=x v a=v=v -Dv=on");
+        let pcv = PhCommandVec::new("run", args, vec!["v"]).value_only(true);
+        assert_eq!(pcv.first().unwrap(), "run key=v {}=x {} a=v=v -D{}=on");
+        assert_eq!(pcv.iter().next().unwrap().expand("{}.log"), "v.log");
+
+        let pcv = PhCommandVec::new("run", vec!["{}"], vec!["a", "b"]).value_only(true);
+        assert!(!pcv.args_has_placeholder());
     }
 
     #[test]
-    #[allow(clippy::useless_vec)]
-    fn test_rows() {
+    fn test_prefix_args() {
+        let pcv = PhCommandVec::new("job", vec!["{}"], vec!["a", "b"])
+            .prefix_args(vec!["--id", "{#}"])
+            .prefix_args(vec!["-v"])
+            .counter(10, 5);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["job --id 10 -v a", "job --id 15 -v b"]);
+        assert_eq!(pcv.template(), ["--id", "{#}", "-v", "{}"]);
+
+        let pcv = PhCommandVec::new("job", vec!["[{}]"], vec!["a", "b"]).prefix_args(vec!["-v"]);
+        assert_eq!(pcv.first().unwrap(), "job -v a b");
+    }
+
+    #[test]
+    fn test_group_by() {
+        let pcv = PhCommandVec::new("cat", vec!["{}"], vec!["a.txt", "b.txt", "c.md"]);
+        let groups = pcv.group_by(|ph| {
+            std::path::Path::new(ph)
+                .extension()
+                .map_or(String::new(), |ext| ext.to_string_lossy().into_owned())
+        });
+        assert_eq!(groups.len(), 2);
+        let strings = |key: &str| {
+            groups[key]
+                .iter()
+                .map(|c| c.command_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(strings("txt"), ["cat a.txt", "cat b.txt"]);
+        assert_eq!(strings("md"), ["cat c.md"]);
+    }
+
+    #[test]
+    fn test_iter_with_strings() {
+        let pcv = PhCommandVec::new("echo", vec!["{#}", "{}"], vec!["a", "b"]);
+        let pairs = pcv.iter_with_strings().collect::<Vec<_>>();
+        assert_eq!(pairs.len(), 2);
+        for (command, command_string) in &pairs {
+            assert_eq!(*command_string, command.command_string());
+        }
+        assert_eq!(pairs[1].1, "echo 2 b");
+    }
+
+    #[test]
+    fn test_raw_accessors() {
+        let pcv = PhCommandVec::new("echo", vec!["-n", "[{}.txt]"], vec!["b", "a"]).sort(true);
+        assert_eq!(pcv.program(), "echo");
+        assert_eq!(pcv.template(), ["-n", "[{}.txt]"]);
+        assert_eq!(pcv.phargs(), ["a", "b"]);
+    }
+
+    #[test]
+    fn test_command_string_marked() {
+        let pcv = PhCommandVec::new("echo", vec!["{}", "b", r"\{}", "x{#}{:1}"], vec!["a"]);
+        let command = pcv.iter().next().unwrap();
         assert_eq!(
-            extend_array("[{}.txt]", &vec!["a", "b"]),
-            vec!["a.txt", "b.txt"]
+            command.command_string_marked("<", ">"),
+            "echo <a> b {} x<1><a>"
         );
         assert_eq!(
-            extend_array("{}.txt", vec!["a".to_string(), "b".to_string()].iter()),
-            vec!["{}.txt"]
+            command.command_string_marked("", ""),
+            command.command_string()
         );
     }
 
     #[test]
-    #[allow(clippy::useless_vec)]
-    fn test_row_has_ph() {
-        assert!(row_has_ph(&vec!["a", "{}"]));
-        assert!(row_has_ph(&vec!["a", "{}.txt"]));
-        assert!(!row_has_ph(&vec!["a", "b", "c"]));
-        let v = vec!["a".to_string(), "b".to_string()];
-        assert!(!row_has_ph(v.iter()));
-        assert!(!row_has_ph(v.iter()));
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("plain"), "plain");
+        assert_eq!(shell_quote("dir/a-1_b.txt"), "dir/a-1_b.txt");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("''"), r"''\'''\'''");
+        assert_eq!(shell_quote("$HOME;*"), "'$HOME;*'");
+        assert_eq!(shell_quote(""), "''");
+
+        #[cfg(unix)]
+        for arg in ["a b", "it's", "$HOME;*", "", "tab\tnewline\n"] {
+            let script = format!("printf %s {}", shell_quote(arg));
+            let output = std::process::Command::new("sh")
+                .args(["-c", &script])
+                .output()
+                .unwrap();
+            assert_eq!(output.stdout, arg.as_bytes());
+        }
     }
 
     #[test]
-    #[allow(clippy::useless_vec)]
-    fn test_extend_row() {
+    fn test_cmd_quote() {
+        assert_eq!(cmd_quote("plain"), "plain");
+        assert_eq!(cmd_quote("a b"), r#""a b""#);
+        assert_eq!(cmd_quote("a&b"), "a^&b");
+        assert_eq!(cmd_quote("x^y|z"), "x^^y^|z");
+        assert_eq!(cmd_quote("100%"), "100^%");
+        assert_eq!(cmd_quote(r#"say "hi""#), r#""say ""hi""""#);
+        assert_eq!(cmd_quote(""), r#""""#);
+
+        let pcv = PhCommandVec::new("echo", vec!["{}", "^"], vec!["a b", "c&d"]);
+        let commands = pcv
+            .iter()
+            .map(|c| c.command_string_cmd())
+            .collect::<Vec<_>>();
+        assert_eq!(commands, [r#"echo "a b" ^^"#, "echo c^&d ^^"]);
+    }
+
+    #[test]
+    fn test_to_vec() {
+        let pcv = PhCommandVec::new("echo", vec!["{#}", "{}"], vec!["a", "c"]);
+        let owned = pcv.to_vec();
         assert_eq!(
-            extend_row(["a", "[{}.txt]"], &["1", "2"]),
-            ["a", "1.txt", "2.txt"]
+            owned.iter().map(|c| c.command_string()).collect::<Vec<_>>(),
+            pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>()
         );
+        assert_eq!(owned[1].ph(), "c");
+        assert_eq!(owned[1].args(), ["2", "c"]);
+    }
+
+    #[test]
+    fn test_to_vec_env() {
+        let env = |mut command: std::process::Command| command.output().unwrap().stdout;
+        let pcv = PhCommandVec::new("env", Vec::<String>::new(), vec!["a"]).clear_env(vec!["PATH"]);
+        let owned = env(pcv.to_vec()[0].command());
+        assert_eq!(owned, env(pcv.iter().next().unwrap().command()));
         assert_eq!(
-            extend_row(
-                vec!["a".to_string(), "[{}.txt]".to_string()].iter(),
-                &vec!["1".to_string(), "2".to_string()]
-            ),
-            vec!["a", "1.txt", "2.txt"]
+            String::from_utf8_lossy(&owned),
+            format!("PATH={}\n", std::env::var("PATH").unwrap())
         );
     }
 
     #[test]
-    fn test_ph_command() {
-        let pc = PhCommand {
-            program: "echo",
-            args: &["{}".to_string(), "b".to_string()],
-            ph: "a".to_string(),
-        };
-        assert_eq!(pc.args(), vec!["a", "b"]);
-        assert_eq!(pc.command_string(), "echo a b");
+    fn test_sort() {
+        let strings =
+            |pcv: &PhCommandVec| pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["9", "10", "1"]).sort(true);
+        assert_eq!(strings(&pcv), ["echo 1", "echo 10", "echo 9"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["9", "10", "1"]).numeric_sort(true);
+        assert_eq!(strings(&pcv), ["echo 1", "echo 9", "echo 10"]);
+
+        let pcv =
+            PhCommandVec::new("echo", vec!["{}"], vec!["b", "10", "a", "9"]).numeric_sort(true);
+        assert_eq!(strings(&pcv), ["echo 9", "echo 10", "echo a", "echo b"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["[{}]"], vec!["9", "10"]).numeric_sort(true);
+        assert_eq!(strings(&pcv), ["echo 9 10"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["b", "a"]).sort(false);
+        assert_eq!(strings(&pcv), ["echo b", "echo a"]);
     }
 
     #[test]
-    fn test_ph_command_vec() {
+    fn test_build_command() {
+        let args = vec!["{}".to_string(), "b".to_string(), r"\{}".to_string()];
+        let command = build_command("echo", &args, "a");
+        assert_eq!(command.get_program(), "echo");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["a", "b", "{}"]);
+
+        let output = build_command("{}", &args, "echo").output().unwrap();
+        assert_eq!(output.stdout, b"echo b {}\n");
+    }
+
+    #[test]
+    fn test_substitute_slice() {
+        assert_eq!(substitute("{:4}", "abcdef"), "abcd");
+        assert_eq!(substitute("{2:}", "abcdef"), "cdef");
+        assert_eq!(substitute("{1:3}", "abcdef"), "bc");
+        assert_eq!(substitute("{1:10}", "abc"), "bc");
+        assert_eq!(substitute("{5:}", "abc"), "");
+        assert_eq!(substitute("{2:1}", "abc"), "");
+        assert_eq!(substitute("{:2}", "äöü"), "äö");
+        assert_eq!(substitute("{a:2} {1:b}", "abc"), "{a:2} {1:b}");
+        assert_eq!(substitute("{{}}", "a"), "{a}");
+
+        assert!(has_placeholder("id-{:4}"));
+        assert!(!has_placeholder("{x}"));
+
+        let pcv = PhCommandVec::new("echo", vec!["{:4}", "{4:}"], vec!["20240622"]);
+        let mut iter = pcv.iter();
+        assert_eq!(iter.next().unwrap().command_string(), "echo 2024 0622");
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_substitute_escape() {
+        assert_eq!(substitute(r"echo \{}", "a"), "echo {}");
+        assert_eq!(substitute(r"echo \\{}", "a"), r"echo \a");
+        assert_eq!(substitute(r"echo \\\{}", "a"), r"echo \{}");
+        assert_eq!(substitute("echo {}", "a"), "echo a");
+        assert_eq!(substitute(r"a\b \{x} \{:1}{}", "a"), r"a\b \{x} {:1}a");
+        assert!(!has_placeholder(r"\{}"));
+
+        let pcv = PhCommandVec::new("echo", vec![r"\{}"], vec!["a", "b"]);
+        assert_eq!(pcv.iter().count(), 1);
+        assert_eq!(pcv.iter().next().unwrap().command_string(), "echo {}");
+    }
+
+    #[test]
+    fn test_substitute_abs() {
+        let file = std::env::temp_dir().join(format!("phargs-abs-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let canonical = std::fs::canonicalize(&file).unwrap();
+        assert_eq!(
+            substitute("{abs}", file.to_str().unwrap()),
+            canonical.to_str().unwrap()
+        );
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(
+            substitute("{abs}", "phargs-no-such-file"),
+            "phargs-no-such-file"
+        );
+    }
+
+    #[test]
+    fn test_substitute_hash_and_safe() {
+        assert_eq!(substitute("{hash}", "hello"), "4f9f2cab");
+        assert_eq!(substitute("{hash}", ""), "811c9dc5");
+        assert_ne!(substitute("{hash}", "a/b"), substitute("{hash}", "a_b"));
+        assert_eq!(substitute("{safe}", "dir/sub/file.txt"), "dir_sub_file.txt");
+        assert_eq!(substitute("{safe}", "a:b*c?\td"), "a_b_c__d");
+    }
+
+    #[test]
+    fn test_substitute_url() {
+        assert_eq!(substitute("{url}", "a b&c"), "a%20b%26c");
+        assert_eq!(substitute("q={url}", "x?y=1/2"), "q=x%3Fy%3D1%2F2");
+        assert_eq!(substitute("{url}", "A-z_0.9~"), "A-z_0.9~");
+        assert_eq!(substitute("{url}", "é%"), "%C3%A9%25");
+    }
+
+    #[test]
+    fn test_field_range() {
+        let pcv = PhCommandVec::new(
+            "echo",
+            vec!["{2:4}", "{4:9}", "{:2}", "{5:}", "{7:8}"],
+            vec!["a,b,c,d,e"],
+        )
+        .field_delimiter(",");
+        let command = pcv.iter().next().unwrap();
+        assert_eq!(command.args(), ["b,c,d", "d,e", "a,b", "e", ""]);
+        assert_eq!(substitute("{2:4}", "a,b,c,d,e"), "b,");
+    }
+
+    #[test]
+    fn test_field_count() {
+        let pcv = PhCommandVec::new(
+            "cut",
+            vec!["--columns", "{#fields}", "[{#fields}]"],
+            vec!["a,b,c", "a", ",", ""],
+        )
+        .field_delimiter(",");
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            [
+                "cut --columns 3 3 1 2 1",
+                "cut --columns 1 3 1 2 1",
+                "cut --columns 2 3 1 2 1",
+                "cut --columns 1 3 1 2 1"
+            ]
+        );
+        assert_eq!(substitute("{#fields}", "a,b"), "{#fields}");
+    }
+
+    #[test]
+    fn test_substitute_rel() {
+        let pcv = PhCommandVec::new("echo", vec!["{rel}"], vec!["/data/in/a.txt", "/tmp/b.txt"])
+            .base("/data");
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo in/a.txt", "echo /tmp/b.txt"]);
+
+        let pcv =
+            PhCommandVec::new("echo", vec!["{rel}"], vec!["/data", "/database"]).base("/data/");
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo .", "echo /database"]);
+
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(substitute("{rel}", cwd.join("x").to_str().unwrap()), "x");
+    }
+
+    #[test]
+    fn test_run_parallel_with_permits() {
+        let log = std::env::temp_dir().join(format!("phargs-permits-{}", std::process::id()));
+        let log = log.to_str().unwrap();
+        let script = "echo start >> $0; sleep 0.05; echo end >> $0";
+        let batch = |phargs| PhCommandVec::new("sh", vec!["-c", script, log, "{}"], phargs);
+        let (first, second) = (batch(vec!["a", "b"]), batch(vec!["c", "d", "e"]));
+        let semaphore = Semaphore::new(1);
+        std::thread::scope(|s| {
+            let first = s.spawn(|| first.run_parallel_with_permits(&semaphore));
+            let second = s.spawn(|| second.run_parallel_with_permits(&semaphore));
+            assert_eq!(first.join().unwrap().len(), 2);
+            assert_eq!(second.join().unwrap().len(), 3);
+        });
+        // With a single permit, no command starts before the previous one ended.
+        let lines = std::fs::read_to_string(log).unwrap();
+        assert_eq!(lines, "start\nend\n".repeat(5));
+        std::fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn test_run_iter() {
+        let dir = std::env::temp_dir().join(format!("phargs-run-iter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pcv = PhCommandVec::new(
+            "sh",
+            vec!["-c", "touch {}"],
+            ["a", "b", "c"]
+                .map(|s| dir.join(s).to_string_lossy().into_owned())
+                .to_vec(),
+        );
+        // Nothing runs until the iterator is advanced.
+        let mut iter = pcv.run_iter();
+        assert!(!dir.join("a").exists());
+        assert!(iter.next().unwrap().1.unwrap().success());
+        assert!(dir.join("a").exists() && !dir.join("b").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let phargs = vec!["true", "no-such-program-for-phargs", "true"];
+        let pcv = PhCommandVec::new("{}", Vec::<String>::new(), phargs);
+        let ran = pcv
+            .run_iter()
+            .take_while(|(_, r)| r.is_ok())
+            .map(|(c, _)| c.command_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ran, ["true"]);
+        let codes = pcv
+            .run_iter()
+            .map(|(_, r)| r.ok().and_then(|s| s.code()))
+            .collect::<Vec<_>>();
+        assert_eq!(codes, [Some(0), None, Some(0)]);
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let log = std::env::temp_dir().join(format!("phargs-batch-{}", std::process::id()));
+        let log = log.to_str().unwrap();
+        let pcv = PhCommandVec::new("sh", vec!["-c", "echo {} >> $0", log], vec!["a", "b"])
+            .pre("sh", vec!["-c", "echo pre > $0", log])
+            .post("sh", vec!["-c", "echo post >> $0", log]);
+        assert_eq!(pcv.run_batch().unwrap().len(), 2);
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "pre\na\nb\npost\n");
+        std::fs::remove_file(log).unwrap();
+
+        let pcv = PhCommandVec::new("sh", vec!["-c", "echo {} >> $0", log], vec!["a"])
+            .pre("false", Vec::<String>::new());
+        assert!(pcv.run_batch().is_err());
+        assert!(!std::path::Path::new(log).exists());
+
+        let pcv = PhCommandVec::new("sh", vec!["-c", "exit {}"], vec!["0", "1", "0"]);
+        assert_eq!(
+            pcv.run_batch_summary().unwrap(),
+            RunSummary {
+                total: 3,
+                succeeded: 1,
+                failed: 1,
+                skipped: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_substitute_metadata() {
+        let file = std::env::temp_dir().join(format!("phargs-size-{}", std::process::id()));
+        std::fs::write(&file, "12345").unwrap();
+        let path = file.to_str().unwrap();
+        assert_eq!(substitute("{size}", path), "5");
+        let mtime = substitute("{mtime}", path).parse::<u64>().unwrap();
+        assert!(mtime > 1_600_000_000);
+
+        let pcv = PhCommandVec::new("echo", vec!["{size}"], vec![path, "phargs-no-such-file"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo 5", "echo 0"]);
+        assert_eq!(pcv.validate(), Ok(()));
+        let pcv = pcv.strict_metadata(true);
+        assert_eq!(pcv.validate(), Err(vec!["{size}".to_string()]));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_run_reduce() {
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["b", "a", "c"])
+            .reduce_with("sort", vec!["-r"]);
+        let output = pcv.run_reduce().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "c\nb\na\n");
+
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["a"]);
+        assert!(pcv.run_reduce().is_err());
+        let pcv = PhCommandVec::new("false", vec!["{}"], vec!["a"])
+            .reduce_with("cat", Vec::<String>::new());
+        assert!(pcv.run_reduce().is_err());
+    }
+
+    #[test]
+    fn test_run_timed() {
+        let pcv = PhCommandVec::new("true", vec!["{}"], vec!["a", "b", "c"]);
+        let results = pcv.run_timed();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[1].0, "true b");
+        for (_, duration, status) in results {
+            assert!(duration > std::time::Duration::ZERO);
+            assert!(status.unwrap().success());
+        }
+    }
+
+    #[test]
+    fn test_max_chars() {
+        let phargs = (0..10).map(|i| format!("f{}", i)).collect::<Vec<_>>();
+        // "echo" + 3 phargs of "fN", each counted with their NUL terminator.
+        let pcv = PhCommandVec::new("echo", vec!["[{}]"], phargs.clone()).max_chars(5 + 3 * 3);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            ["echo f0 f1 f2", "echo f3 f4 f5", "echo f6 f7 f8", "echo f9"]
+        );
+        assert_eq!(pcv.len(), 4);
+
+        let pcv = PhCommandVec::new("echo", vec!["-a", "[{}]"], phargs.clone()).max_chars(1);
+        assert_eq!(pcv.iter().count(), 10);
+        assert_eq!(pcv.len(), 10);
+        assert_eq!(pcv.iter().nth(9).unwrap().command_string(), "echo -a f9");
+
+        let pcv = PhCommandVec::new("echo", vec!["[{}]"], phargs);
+        assert_eq!(pcv.iter().count(), 1);
+        assert_eq!(pcv.len(), 1);
+        assert!(PhCommandVec::new("echo", vec!["{}"], Vec::<String>::new()).is_empty());
+    }
+
+    #[test]
+    fn test_missing_programs() {
         let pcv = PhCommandVec::new(
+            "{}",
+            vec!["-c", "true"],
+            vec![
+                "sh",
+                "phargs-no-such-program",
+                "sh",
+                "phargs-no-such-program",
+            ],
+        );
+        assert_eq!(pcv.iter().next().unwrap().program(), "{}");
+        assert_eq!(pcv.iter().next().unwrap().expanded_program(), "sh");
+        assert_eq!(pcv.iter().count(), 4);
+        assert_eq!(pcv.missing_programs(), ["phargs-no-such-program"]);
+
+        let pcv = PhCommandVec::new("sh", vec!["-c", "true"], vec!["a"]);
+        assert!(pcv.missing_programs().is_empty());
+    }
+
+    #[test]
+    fn test_run_prefixed() {
+        let pcv = PhCommandVec::new("printf", vec![r"{}-1\n{}-2\n"], vec!["a", "b"]);
+        let mut out = Vec::new();
+        for c in pcv.iter() {
+            assert!(c.run_prefixed("[{#}:{}] ", &mut out).unwrap().success());
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[1:a] a-1\n[1:a] a-2\n[2:b] b-1\n[2:b] b-2\n"
+        );
+
+        let pcv = PhCommandVec::new("echo", vec!["{#}"], vec!["a", "b"]);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo 1", "echo 2"]);
+    }
+
+    #[test]
+    fn test_dedup_commands() {
+        let strings =
+            |pcv: &PhCommandVec| pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        let pcv = PhCommandVec::new("echo", vec!["{:1}"], vec!["ab", "ac", "b", "a"]);
+        assert_eq!(strings(&pcv), ["echo a", "echo a", "echo b", "echo a"]);
+        assert_eq!(strings(&pcv.dedup_commands(true)), ["echo a", "echo b"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["x"], vec!["a", "b"]).dedup_commands(true);
+        assert_eq!(strings(&pcv), ["echo x"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["{:1}"], vec!["ab", "ac"]).dedup_commands(false);
+        assert_eq!(pcv.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(extend_array_fields("[{1}.{2}]", ["a:txt"], ":"), ["a.txt"]);
+        assert_eq!(extend_array("[{1}.{2}]", ["a:txt"]), ["{1}.{2}"]);
+        assert_eq!(
+            extend_row_fields(["x", "[{2}/{1}]", "{1}"], &["a:b", "c"], ":"),
+            ["x", "b/a", "/c", "{1}"]
+        );
+
+        let pcv = PhCommandVec::new("cp", vec!["{1}", "{2}.{3}", "{0}"], vec!["a:b", "c:d:e"])
+            .field_delimiter(":");
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["cp a b. {0}", "cp c d.e {0}"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["[{1}.{2}]"], vec!["a:txt", "b:md"])
+            .field_delimiter(":");
+        assert_eq!(
+            pcv.iter().next().unwrap().command_string(),
+            "echo a.txt b.md"
+        );
+
+        let pcv = PhCommandVec::new("echo", vec!["{1}"], vec!["a", "b"]);
+        assert_eq!(pcv.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_sub_delimiter() {
+        let pcv = PhCommandVec::new("echo", vec!["[{1}]"], vec!["a|b|c"])
+            .field_delimiter(":")
+            .sub_delimiter("|");
+        assert_eq!(pcv.iter().next().unwrap().argv(), ["echo", "a", "b", "c"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["[-{1}.{2}]", "[{}]"], vec!["a|b:t", "c:u"])
+            .field_delimiter(":")
+            .sub_delimiter("|");
+        assert_eq!(
+            pcv.iter().next().unwrap().command_string(),
+            "echo -a.t -b.t -c.u a|b:t c:u"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let pcv = PhCommandVec::new("echo", vec!["{1}"], vec!["a", "b"]).field_delimiter(":");
+        assert_eq!(pcv.validate(), Ok(()));
+
+        let pcv = PhCommandVec::new("echo", vec!["{1}", "[{3}]", "{0}"], vec!["a", "b"])
+            .field_delimiter(":");
+        assert_eq!(pcv.validate(), Err(vec!["{3}".to_string()]));
+
+        let pcv = PhCommandVec::new("echo", vec!["{3}"], vec!["a"]);
+        assert_eq!(pcv.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_stdio() {
+        let pcv = PhCommandVec::new("cat", vec!["{}"], vec!["-"]).stdio(StdioMode::Null);
+        let output = pcv.iter().next().unwrap().command().output().unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+
+        // `output()` captures stdout unless the mode overrides it.
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["a"]);
+        let output = pcv.iter().next().unwrap().command().output().unwrap();
+        assert_eq!(output.stdout, b"a\n");
+
+        let pcv = pcv.stdio(StdioMode::Null);
+        let output = pcv.iter().next().unwrap().command().output().unwrap();
+        assert!(output.stdout.is_empty());
+    }
+
+    #[test]
+    fn test_substitution_count() {
+        let pcv = PhCommandVec::new("echo", vec!["{}", "{}"], vec!["a"]);
+        assert_eq!(pcv.iter().next().unwrap().substitution_count(), 2);
+
+        let pcv = PhCommandVec::new("echo", vec!["{}{#}", "-", "{:1}"], vec!["a"]);
+        assert_eq!(pcv.iter().next().unwrap().substitution_count(), 3);
+
+        let pcv = PhCommandVec::new("echo", vec!["x"], vec!["a"]);
+        assert_eq!(pcv.iter().next().unwrap().substitution_count(), 0);
+    }
+
+    #[test]
+    fn test_pipe_to() {
+        let pcv =
+            PhCommandVec::new("echo", vec!["{}"], vec!["hi", "there"]).pipe_to("cat", vec!["-"]);
+        let outputs = pcv.iter().map(|c| c.output().unwrap()).collect::<Vec<_>>();
+        assert_eq!(outputs[0].stdout, b"hi\n");
+        assert_eq!(outputs[1].stdout, b"there\n");
+
+        let pcv =
+            PhCommandVec::new("echo", vec!["{}"], vec!["hi"]).pipe_to("grep", vec!["-q", "x{}"]);
+        assert!(!pcv.iter().next().unwrap().status().unwrap().success());
+        let pcv =
+            PhCommandVec::new("echo", vec!["x{}"], vec!["hi"]).pipe_to("grep", vec!["-q", "x{}"]);
+        assert!(pcv.iter().next().unwrap().status().unwrap().success());
+    }
+
+    #[test]
+    fn test_counter() {
+        let pcv = PhCommandVec::new("echo", vec!["{#}"], vec!["a", "b", "c"]).counter(100, 10);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo 100", "echo 110", "echo 120"]);
+
+        let pcv = PhCommandVec::new("echo", vec!["{#}"], vec!["a", "b"]).counter(0, -1);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo 0", "echo -1"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_shell_command() {
+        let (program, args) = shell_command("/bin/zsh", &[]);
+        let pcv = PhCommandVec::new(program, args, vec!["ls", "pwd"]);
+        let argvs = pcv
+            .iter()
+            .map(|c| [vec![c.expanded_program()], c.args()].concat())
+            .collect::<Vec<_>>();
+        assert_eq!(argvs, [["/bin/zsh", "-c", "ls"], ["/bin/zsh", "-c", "pwd"]]);
+
+        let command = [
             "echo".to_string(),
-            vec!["{}".to_string(), "b".to_string()],
-            vec!["a".to_string(), "c".to_string()],
+            "{}".to_string(),
+            "|".to_string(),
+            "tr".to_string(),
+        ];
+        assert_eq!(shell_command("sh", &command).1, ["-c", "echo {} | tr"]);
+    }
+
+    #[test]
+    fn test_named() {
+        let lists = std::collections::HashMap::from([
+            ("src".to_string(), vec!["b".to_string(), "a".to_string()]),
+            ("dst".to_string(), vec!["y".to_string(), "x".to_string()]),
+        ]);
+        let pcv = PhCommandVec::named("cp", vec!["{src}", "{dst}"], lists.clone());
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["cp b y", "cp a x"]);
+
+        let pcv = PhCommandVec::named("cp", vec!["{src}", "{dst}", "{other}"], lists).sort(true);
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["cp a x {other}", "cp b y {other}"]);
+
+        let lists = std::collections::HashMap::from([
+            ("a".to_string(), vec!["1".to_string()]),
+            ("b".to_string(), vec![]),
+        ]);
+        assert_eq!(
+            PhCommandVec::named("echo", vec!["{a}"], lists)
+                .iter()
+                .count(),
+            0
         );
-        let mut iter = pcv.iter();
-        assert_eq!(iter.next().unwrap().command_string(), "echo a b");
-        assert_eq!(iter.next().unwrap().command_string(), "echo c b");
-        assert!(iter.next().is_none());
+    }
 
-        let pcv = PhCommandVec::new("echo", vec!["[{}.txt]", "b"], vec!["a", "c"]);
-        let mut iter = pcv.iter();
-        assert_eq!(iter.next().unwrap().command_string(), "echo a.txt c.txt b");
-        assert!(iter.next().is_none());
+    #[test]
+    fn test_template_args() {
+        let pcv = PhCommandVec::new(
+            "echo",
+            vec!["a", "{}", "[{}.txt]", "{x}", "[b]", "{1}"],
+            vec!["a"],
+        );
+        assert_eq!(pcv.template_args(), [false, true, true, false, true, false]);
+        let pcv = pcv.field_delimiter(",");
+        assert!(pcv.template_args()[5]);
+    }
 
-        let pcv = PhCommandVec::new("echo", vec!["[{}.txt]", "{}"], vec!["a", "c"]);
-        let mut iter = pcv.iter();
-        assert_eq!(iter.next().unwrap().command_string(), "echo a.txt c.txt a");
-        assert_eq!(iter.next().unwrap().command_string(), "echo a.txt c.txt c");
-        assert!(iter.next().is_none());
+    #[test]
+    fn test_join() {
+        let pcv = PhCommandVec::new("echo", vec!["{}"], vec!["a", "b", "c"]).join("+");
+        let commands = pcv.iter().map(|c| c.command_string()).collect::<Vec<_>>();
+        assert_eq!(commands, ["echo a+b+c"]);
+    }
+
+    #[test]
+    fn test_stream_output() {
+        let pcv = PhCommandVec::new(
+            "sh",
+            vec!["-c", "yes {} | head -n 100000; echo done >&2"],
+            vec!["x"],
+        );
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let status = pcv
+            .iter()
+            .next()
+            .unwrap()
+            .stream_output(&mut out, &mut err)
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(out.len(), 200000);
+        assert_eq!(err, b"done\n");
+    }
+
+    #[test]
+    fn test_output_merged() {
+        let pcv = PhCommandVec::new(
+            "sh",
+            vec!["-c", "echo out; echo {} >&2; echo end"],
+            vec!["err"],
+        );
+        let (status, output) = pcv.iter().next().unwrap().output_merged().unwrap();
+        assert!(status.success());
+        assert_eq!(String::from_utf8_lossy(&output), "out\nerr\nend\n");
+    }
+
+    #[test]
+    fn test_run_until_success() {
+        let log = std::env::temp_dir().join(format!("phargs-first-success-{}", std::process::id()));
+        let script = format!("echo {{}} >> {}; exit {{}}", log.display());
+        let pcv = PhCommandVec::new("sh", vec!["-c", &script], vec!["1", "0", "0"]);
+        let (command, status) = pcv.run_until_success().unwrap();
+        assert!(status.success());
+        assert!(command.ends_with("exit 0"));
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "1\n0\n");
+        std::fs::remove_file(&log).unwrap();
+
+        let pcv = PhCommandVec::new("sh", vec!["-c", "exit {}"], vec!["1", "2"]);
+        assert!(pcv.run_until_success().is_none());
+        let (command, _) = pcv
+            .run_until_success_by(|_, result| result.as_ref().is_ok_and(|s| s.code() == Some(2)))
+            .unwrap();
+        assert_eq!(command, "sh -c exit 2");
+
+        let pcv = PhCommandVec::new("{}", Vec::<String>::new(), vec!["/nonexistent", "true"]);
+        let mut errors = Vec::new();
+        let success = pcv.run_until_success_by(|c, result| match result {
+            Ok(status) => status.success(),
+            Err(_) => {
+                errors.push(c.command_string());
+                true
+            }
+        });
+        assert_eq!(success.unwrap().0, "true");
+        assert_eq!(errors, ["/nonexistent"]);
     }
 }
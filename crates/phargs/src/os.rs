@@ -0,0 +1,151 @@
+use crate::{scan, Config, Context};
+use std::ffi::{OsStr, OsString};
+use std::process::Command;
+
+/// Substitutes placeholders in `template` with a pharg that may not be valid UTF-8.
+///
+/// `{}` is replaced with `ph` unchanged. The other placeholders of
+/// [`substitute`](crate::substitute) see `ph` converted lossily.
+///
+/// # Examples
+///
+/// ```
+/// use std::ffi::OsStr;
+/// assert_eq!(phargs::substitute_os("{}.txt", OsStr::new("file1")), "file1.txt");
+/// ```
+pub fn substitute_os(template: &str, ph: &OsStr) -> OsString {
+    substitute_os_in(&Context::default(), template, ph)
+}
+
+fn substitute_os_in(ctx: &Context, template: &str, ph: &OsStr) -> OsString {
+    let lossy = ph.to_string_lossy();
+    let ctx = Context { ph: &lossy, ..*ctx };
+    scan(template, |token| match token {
+        "" => Some(ph.to_os_string()),
+        _ => ctx.expand(token).map(OsString::from),
+    })
+    .0
+}
+
+/// A command with placeholders whose pharg is an `OsString`.
+///
+/// This is the counterpart of [`PhCommand`](crate::PhCommand) for phargs such
+/// as file names, which are not necessarily valid UTF-8.
+pub struct PhOsCommand<'a> {
+    program: &'a str,
+    args: &'a [String],
+    ph: &'a OsStr,
+    seq: usize,
+    config: &'a Config,
+}
+
+impl PhOsCommand<'_> {
+    fn expand(&self, template: &str) -> OsString {
+        let ctx = self.config.context("", Some(self.seq));
+        substitute_os_in(&ctx, template, self.ph)
+    }
+
+    /// Returns the program with placeholders substituted.
+    pub fn program(&self) -> OsString {
+        self.expand(self.program)
+    }
+
+    /// Returns the arguments with placeholders substituted.
+    pub fn args(&self) -> Vec<OsString> {
+        self.args.iter().map(|s| self.expand(s)).collect()
+    }
+
+    /// Creates a `Command` ready to execute.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(self.program());
+        command.args(self.args());
+        self.config.apply(&mut command);
+        command
+    }
+}
+
+/// A list of commands, one per `OsString` pharg.
+///
+/// Unlike [`PhCommandVec`](crate::PhCommandVec), every pharg gets its own
+/// command; there is no `[..]` chunking.
+///
+/// # Examples
+///
+/// ```
+/// let commands = phargs::PhOsCommandVec::new("echo", vec!["{#}", "{}"], vec!["a".into()]);
+/// let args: Vec<_> = commands.iter().map(|c| c.args()).collect();
+/// assert_eq!(args, vec![vec!["1", "a"]]);
+/// ```
+pub struct PhOsCommandVec {
+    program: String,
+    args: Vec<String>,
+    phargs: Vec<OsString>,
+    config: Config,
+}
+
+impl PhOsCommandVec {
+    pub fn new<P: Into<String>, A: Into<String>>(
+        program: P,
+        args: Vec<A>,
+        phargs: Vec<OsString>,
+    ) -> Self {
+        PhOsCommandVec {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            phargs,
+            config: Config::default(),
+        }
+    }
+
+    /// Splits each pharg into fields `{1}`, `{2}`, ... by `delimiter`.
+    pub fn field_delimiter<D: Into<String>>(mut self, delimiter: D) -> Self {
+        self.config.delimiter = Some(delimiter.into());
+        self
+    }
+
+    /// Sets the counter `{#}` to `start` for the first command and increments it by `step`.
+    pub fn counter(mut self, start: i64, step: i64) -> Self {
+        self.config.count_start = start;
+        self.config.count_step = step;
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = PhOsCommand<'_>> {
+        self.phargs.iter().enumerate().map(|(i, ph)| PhOsCommand {
+            program: &self.program,
+            args: &self.args,
+            ph,
+            seq: i + 1,
+            config: &self.config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substitute_os() {
+        let ph = OsStr::new("20240622");
+        assert_eq!(substitute_os("{}-{:4}", ph), "20240622-2024");
+        assert_eq!(substitute_os("{#}", ph), "{#}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_round_trip() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        let bytes = b"caf\xe9.txt".to_vec();
+        let commands = PhOsCommandVec::new(
+            "printf",
+            vec!["%s", "{}"],
+            vec![OsString::from_vec(bytes.clone())],
+        );
+        let command = commands.iter().next().unwrap();
+        assert_eq!(command.args()[1].as_bytes(), &bytes[..]);
+        let output = command.command().output().unwrap();
+        assert_eq!(output.stdout, bytes);
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::{self, BufRead, Write};
+
+/// Writes a plan of commands to review and run later with [`read_plan`].
+///
+/// Each argv of `argvs` is written as a JSON array of strings on its own line.
+pub fn write_plan<W: Write>(mut out: W, argvs: &[Vec<String>]) -> io::Result<()> {
+    for argv in argvs {
+        serde_json::to_writer(&mut out, argv)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// Reads back a plan written by [`write_plan`]. Empty lines are skipped.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` error naming the line that isn't a JSON array of strings.
+///
+/// # Examples
+///
+/// ```
+/// let mut plan = Vec::new();
+/// phargs::write_plan(&mut plan, &[vec!["echo".to_string(), "a b".to_string()]]).unwrap();
+/// assert_eq!(String::from_utf8_lossy(&plan), "[\"echo\",\"a b\"]\n");
+/// assert_eq!(phargs::read_plan(&plan[..]).unwrap(), [["echo", "a b"]]);
+/// ```
+pub fn read_plan<R: BufRead>(reader: R) -> io::Result<Vec<Vec<String>>> {
+    let mut argvs = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let argv = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected a JSON array of strings: {}", i + 1, e),
+            )
+        })?;
+        argvs.push(argv);
+    }
+    Ok(argvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_round_trip() {
+        let pcv = crate::PhCommandVec::new("cp", vec!["{}", "{}.bak"], vec!["a", "b c"]);
+        let argvs = pcv.iter().map(|c| c.argv()).collect::<Vec<_>>();
+        let mut plan = Vec::new();
+        write_plan(&mut plan, &argvs).unwrap();
+        assert_eq!(read_plan(&plan[..]).unwrap(), argvs);
+
+        let err = read_plan("[\"a\"]\n{}\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"));
+    }
+}
@@ -0,0 +1,523 @@
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Options controlling how a batch of commands is run.
+///
+/// # Examples
+///
+/// ```
+/// let options = phargs::RunOptions::default().jobs(4).keep_going(true);
+/// let statuses = phargs::run_argvs(vec![vec!["true".to_string()]], options);
+/// assert!(statuses[0].as_ref().unwrap().success());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    jobs: usize,
+    keep_going: bool,
+    timeout: Option<Duration>,
+    rate: Option<f64>,
+}
+
+impl RunOptions {
+    /// Runs up to `jobs` commands at once. `0` and `1` both run serially.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Keeps starting commands after one failed, instead of stopping.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Kills commands that run longer than `timeout`.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Starts at most `rate` commands per second, see [`RateLimiter`].
+    pub fn rate(mut self, rate: Option<f64>) -> Self {
+        self.rate = rate;
+        self
+    }
+}
+
+/// Paces a sequence of starts so that at most a given number happen per second.
+///
+/// # Examples
+///
+/// ```
+/// let mut limiter = phargs::RateLimiter::new(100.0);
+/// let start = std::time::Instant::now();
+/// for _ in 0..3 {
+///     limiter.wait();
+/// }
+/// assert!(start.elapsed() >= std::time::Duration::from_millis(20));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// The longest interval between starts, a century, which keeps the time
+    /// of the next start representable for any positive rate.
+    const MAX_INTERVAL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+    /// Allows `per_second` starts per second.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` is not a positive number.
+    pub fn new(per_second: f64) -> Self {
+        assert!(per_second > 0.0, "rate must be positive");
+        let interval = Duration::try_from_secs_f64(1.0 / per_second)
+            .map_or(Self::MAX_INTERVAL, |interval| {
+                interval.min(Self::MAX_INTERVAL)
+            });
+        RateLimiter {
+            interval,
+            next: None,
+        }
+    }
+
+    /// Sleeps until the next start is allowed. The first call returns at once.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let start = match self.next {
+            Some(next) if next > now => {
+                std::thread::sleep(next - now);
+                next
+            }
+            _ => now,
+        };
+        self.next = Some(start + self.interval);
+    }
+}
+
+/// Counts of how a batch of commands went.
+///
+/// # Examples
+///
+/// ```
+/// let statuses = phargs::run_argvs(vec![vec!["true".to_string()]], Default::default());
+/// let summary = phargs::RunSummary::new(2, &statuses);
+/// assert_eq!(summary.to_string(), "total=2 succeeded=1 failed=0 skipped=1");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Commands that were never started, for example after a failure.
+    pub skipped: usize,
+}
+
+impl RunSummary {
+    /// Summarizes the `results` of a batch of `total` commands.
+    ///
+    /// Errors, such as commands that couldn't be spawned or timed out, count
+    /// as failures.
+    pub fn new(total: usize, results: &[io::Result<ExitStatus>]) -> Self {
+        Self::with_success(total, results, ExitStatus::success)
+    }
+
+    /// Same as [`new`](Self::new), but `is_success` decides which statuses succeeded.
+    pub fn with_success(
+        total: usize,
+        results: &[io::Result<ExitStatus>],
+        is_success: impl Fn(&ExitStatus) -> bool,
+    ) -> Self {
+        let succeeded = results
+            .iter()
+            .filter(|r| r.as_ref().is_ok_and(&is_success))
+            .count();
+        RunSummary {
+            total,
+            succeeded,
+            failed: results.len() - succeeded,
+            skipped: total.saturating_sub(results.len()),
+        }
+    }
+}
+
+impl std::fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "total={} succeeded={} failed={} skipped={}",
+            self.total, self.succeeded, self.failed, self.skipped
+        )
+    }
+}
+
+/// Random delays in a range, for spreading out commands.
+///
+/// The delays come from a small built-in generator (SplitMix64), so the same
+/// seed always gives the same sequence.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let (min, max) = (Duration::from_millis(10), Duration::from_millis(20));
+/// let mut jitter = phargs::Jitter::new(min, max, 42);
+/// let delay = jitter.delay();
+/// assert!(min <= delay && delay <= max);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Jitter {
+    min: Duration,
+    max: Duration,
+    state: u64,
+}
+
+impl Jitter {
+    /// Picks delays between `min` and `max`, both inclusive. A `max` below
+    /// `min` always gives `min`.
+    pub fn new(min: Duration, max: Duration, seed: u64) -> Self {
+        Jitter {
+            min,
+            max: max.max(min),
+            state: seed,
+        }
+    }
+
+    /// Returns the next random delay.
+    pub fn delay(&mut self) -> Duration {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        let unit = (z >> 11) as f64 / (1u64 << 53) as f64;
+        self.min + (self.max - self.min).mul_f64(unit)
+    }
+
+    /// Sleeps for the next random delay.
+    pub fn sleep(&mut self) {
+        std::thread::sleep(self.delay());
+    }
+}
+
+/// Runs prebuilt argv vectors, each of the form `[program, args...]`.
+///
+/// Commands are run according to `opts`. Unless `keep_going` is set, no new
+/// command is started once one has failed.
+///
+/// # Returns
+///
+/// Returns the status of every command that was started, in input order.
+/// An empty argv and a timed out command are reported as errors.
+pub fn run_argvs(cmds: Vec<Vec<String>>, opts: RunOptions) -> Vec<io::Result<ExitStatus>> {
+    let commands = cmds.into_iter().map(|argv| {
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty argv"))?;
+        let mut command = Command::new(program);
+        command.args(args);
+        Ok(command)
+    });
+    run_commands(commands, &opts)
+}
+
+/// Same as [`run_argvs`], but returns how the batch went instead of the
+/// status of each command. Commands never started count as skipped.
+///
+/// # Examples
+///
+/// ```
+/// let cmds = vec![vec!["false".to_string()], vec!["true".to_string()]];
+/// let summary = phargs::run_argvs_summary(cmds, Default::default());
+/// assert_eq!(summary.to_string(), "total=2 succeeded=0 failed=1 skipped=1");
+/// ```
+pub fn run_argvs_summary(cmds: Vec<Vec<String>>, opts: RunOptions) -> RunSummary {
+    let total = cmds.len();
+    RunSummary::new(total, &run_argvs(cmds, opts))
+}
+
+/// A pool of permits bounding how many commands run at once, which can be
+/// shared by several batches, see [`PhCommandVec::run_parallel_with_permits`].
+///
+/// [`PhCommandVec::run_parallel_with_permits`]: crate::PhCommandVec::run_parallel_with_permits
+///
+/// # Examples
+///
+/// ```
+/// let semaphore = phargs::Semaphore::new(1);
+/// let permit = semaphore.acquire();
+/// assert!(semaphore.try_acquire().is_none());
+/// drop(permit);
+/// assert!(semaphore.try_acquire().is_some());
+/// ```
+#[derive(Debug)]
+pub struct Semaphore {
+    capacity: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A permit of a [`Semaphore`], given back when dropped.
+#[derive(Debug)]
+pub struct SemaphorePermit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    /// Creates a pool of `permits` permits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `permits` is zero, since nothing could ever run.
+    pub fn new(permits: usize) -> Self {
+        assert!(permits > 0, "a semaphore needs at least one permit");
+        Semaphore {
+            capacity: permits,
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Returns the total number of permits.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Waits until a permit is available and takes it.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        SemaphorePermit(self)
+    }
+
+    /// Takes a permit if one is available, without waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut available = self.available.lock().unwrap();
+        if *available == 0 {
+            return None;
+        }
+        *available -= 1;
+        Some(SemaphorePermit(self))
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Runs `commands` according to `opts`, see [`run_argvs`].
+pub(crate) fn run_commands(
+    commands: impl Iterator<Item = io::Result<Command>> + Send,
+    opts: &RunOptions,
+) -> Vec<io::Result<ExitStatus>> {
+    run_each(commands, opts, None, |command| {
+        command.and_then(|mut c| wait_timeout(&mut c.spawn()?, opts.timeout))
+    })
+}
+
+/// Runs each of `items` with `run` according to `opts`, holding a permit of
+/// `permits` while it runs, if given.
+pub(crate) fn run_each<T: Send>(
+    items: impl Iterator<Item = T> + Send,
+    opts: &RunOptions,
+    permits: Option<&Semaphore>,
+    run: impl Fn(T) -> io::Result<ExitStatus> + Sync,
+) -> Vec<io::Result<ExitStatus>> {
+    let queue = Mutex::new(items.enumerate());
+    let results = Mutex::new(Vec::new());
+    let failed = AtomicBool::new(false);
+    let limiter = opts.rate.map(|rate| Mutex::new(RateLimiter::new(rate)));
+
+    let worker = || loop {
+        if failed.load(Ordering::SeqCst) && !opts.keep_going {
+            break;
+        }
+        let Some((i, item)) = queue.lock().unwrap().next() else {
+            break;
+        };
+        let permit = permits.map(Semaphore::acquire);
+        if let Some(limiter) = &limiter {
+            limiter.lock().unwrap().wait();
+        }
+        let result = run(item);
+        drop(permit);
+        if !matches!(&result, Ok(status) if status.success()) {
+            failed.store(true, Ordering::SeqCst);
+        }
+        results.lock().unwrap().push((i, result));
+    };
+    std::thread::scope(|s| {
+        for _ in 1..opts.jobs {
+            s.spawn(worker);
+        }
+        worker();
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Waits for `child`, killing it once `timeout` has elapsed.
+pub(crate) fn wait_timeout(child: &mut Child, timeout: Option<Duration>) -> io::Result<ExitStatus> {
+    let Some(timeout) = timeout else {
+        return child.wait();
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "command timed out"));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(argv: &[&str]) -> Vec<String> {
+        argv.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_run_argvs() {
+        let statuses = run_argvs(
+            vec![argv(&["true"]), argv(&["sh", "-c", "exit 3"])],
+            RunOptions::default(),
+        );
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses[0].as_ref().unwrap().success());
+        assert_eq!(statuses[1].as_ref().unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn test_run_argvs_stop_and_keep_going() {
+        let cmds = vec![argv(&["false"]), argv(&["true"]), vec![]];
+        let statuses = run_argvs(cmds.clone(), RunOptions::default());
+        assert_eq!(statuses.len(), 1);
+
+        let statuses = run_argvs(cmds, RunOptions::default().keep_going(true));
+        assert_eq!(statuses.len(), 3);
+        assert!(statuses[1].as_ref().unwrap().success());
+        assert_eq!(
+            statuses[2].as_ref().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_run_summary() {
+        let results = run_argvs(
+            vec![argv(&["true"]), argv(&["false"]), vec![], argv(&["true"])],
+            RunOptions::default().keep_going(true),
+        );
+        let summary = RunSummary::new(5, &results);
+        assert_eq!(
+            summary,
+            RunSummary {
+                total: 5,
+                succeeded: 2,
+                failed: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "total=5 succeeded=2 failed=2 skipped=1"
+        );
+        let summary = RunSummary::with_success(5, &results, |_| true);
+        assert_eq!((summary.succeeded, summary.failed), (3, 1));
+    }
+
+    #[test]
+    fn test_run_argvs_summary() {
+        let cmds = vec![argv(&["true"]), argv(&["false"]), vec![], argv(&["true"])];
+        let summary = run_argvs_summary(cmds.clone(), RunOptions::default());
+        assert_eq!(
+            summary,
+            RunSummary {
+                total: 4,
+                succeeded: 1,
+                failed: 1,
+                skipped: 2
+            }
+        );
+        let summary = run_argvs_summary(cmds, RunOptions::default().keep_going(true));
+        assert_eq!(
+            summary.to_string(),
+            "total=4 succeeded=2 failed=2 skipped=0"
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_tiny_rate() {
+        let mut limiter = RateLimiter::new(1e-20);
+        assert_eq!(limiter.interval, RateLimiter::MAX_INTERVAL);
+        limiter.wait();
+        assert!(limiter.next.is_some());
+    }
+
+    #[test]
+    fn test_run_argvs_rate() {
+        let start = Instant::now();
+        let statuses = run_argvs(
+            (0..5).map(|_| argv(&["true"])).collect(),
+            RunOptions::default().jobs(5).rate(Some(20.0)),
+        );
+        assert_eq!(statuses.len(), 5);
+        // Five starts at 20 per second take at least four intervals of 50ms.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let (min, max) = (Duration::from_millis(100), Duration::from_millis(300));
+        let delays = |seed| {
+            let mut jitter = Jitter::new(min, max, seed);
+            (0..100).map(|_| jitter.delay()).collect::<Vec<_>>()
+        };
+        let first = delays(7);
+        assert!(first.iter().all(|d| (min..=max).contains(d)));
+        assert_eq!(first, delays(7));
+        assert_ne!(first, delays(8));
+        assert!(first.iter().any(|d| *d != first[0]));
+
+        let mut fixed = Jitter::new(max, min, 7);
+        assert_eq!(fixed.delay(), max);
+    }
+
+    #[test]
+    fn test_run_argvs_parallel_and_timeout() {
+        let cmds = (0..4).map(|_| argv(&["sleep", "0.2"])).collect();
+        let start = Instant::now();
+        let statuses = run_argvs(cmds, RunOptions::default().jobs(4));
+        assert!(start.elapsed() < Duration::from_millis(700));
+        assert!(statuses.iter().all(|s| s.as_ref().unwrap().success()));
+
+        let statuses = run_argvs(
+            vec![argv(&["sleep", "5"])],
+            RunOptions::default().timeout(Some(Duration::from_millis(50))),
+        );
+        assert_eq!(
+            statuses[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
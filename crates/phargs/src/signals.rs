@@ -0,0 +1,79 @@
+use std::io;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+/// The last signal received since [`forward_signals`], or 0.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// The signals [`forward_signals`] handles.
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+extern "C" fn record(signal: libc::c_int) {
+    RECEIVED.store(signal, Ordering::SeqCst);
+}
+
+/// Catches SIGINT, SIGTERM and SIGHUP instead of being killed by them, so
+/// that [`wait_forwarding`] can pass them on to the running child.
+///
+/// This is only available on Unix.
+pub fn forward_signals() -> io::Result<()> {
+    for signal in SIGNALS {
+        // SAFETY: the handler only stores into an atomic, which is
+        // async-signal-safe, and the sigaction is fully initialized.
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = record as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the last signal caught since [`forward_signals`], if any.
+pub fn received_signal() -> Option<i32> {
+    Some(RECEIVED.load(Ordering::SeqCst)).filter(|&signal| signal != 0)
+}
+
+/// Waits for `child`, sending it every signal caught by [`forward_signals`]
+/// in the meantime.
+pub fn wait_forwarding(child: &mut Child) -> io::Result<ExitStatus> {
+    let mut forwarded = 0;
+    loop {
+        let signal = RECEIVED.swap(0, Ordering::SeqCst);
+        if signal != 0 {
+            // SAFETY: kill has no memory safety preconditions.
+            unsafe { libc::kill(child.id() as libc::pid_t, signal) };
+            forwarded = signal;
+        }
+        if let Some(status) = child.try_wait()? {
+            if forwarded != 0 {
+                RECEIVED.store(forwarded, Ordering::SeqCst);
+            }
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+    #[test]
+    fn test_signal_process_group() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("5")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        // SAFETY: kill has no memory safety preconditions.
+        unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGTERM) };
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+    }
+}
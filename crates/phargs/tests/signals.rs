@@ -0,0 +1,21 @@
+//! [`phargs::forward_signals`] installs process-wide handlers and the test
+//! signals its own process, so it runs in a test binary of its own.
+#![cfg(unix)]
+
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::time::Duration;
+
+#[test]
+fn test_wait_forwarding() {
+    phargs::forward_signals().unwrap();
+    let mut child = Command::new("sleep").arg("5").spawn().unwrap();
+    std::thread::spawn(|| {
+        std::thread::sleep(Duration::from_millis(100));
+        // SAFETY: kill has no memory safety preconditions.
+        unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+    });
+    let status = phargs::wait_forwarding(&mut child).unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+    assert_eq!(phargs::received_signal(), Some(libc::SIGTERM));
+}